@@ -16,8 +16,11 @@ use serde_json::{json, Map, Value};
 use sha2::Digest;
 
 use crate::api::{ApiName, ApiUrl, URL_GET_COMPANY};
-use crate::config::{Config, WgConf, PLATFORM_CORPLINK, PLATFORM_LARK, PLATFORM_LDAP};
+use crate::config::{
+    Config, Mtu, Peer, Protocol, WgConf, PLATFORM_CORPLINK, PLATFORM_LARK, PLATFORM_LDAP,
+};
 use crate::resp::*;
+use crate::route;
 use crate::state::State;
 use crate::totp::{totp_offset, TIME_STEP};
 use crate::utils;
@@ -93,11 +96,7 @@ impl Client {
             Some(dir) => dir,
             None => path::Path::new("."),
         };
-        let cookie_file = dir.join(format!(
-            "{}_{}",
-            conf.interface_name.clone().unwrap(),
-            COOKIE_FILE_SUFFIX
-        ));
+        let cookie_file = dir.join(format!("{}_{}", conf.profile_name(), COOKIE_FILE_SUFFIX));
         log::info!("cookie file is: {}", cookie_file.to_str().unwrap());
 
         let mut cookie_store = {
@@ -173,7 +172,7 @@ impl Client {
             .append(false)
             .open(format!(
                 "{}_{}",
-                self.conf.interface_name.clone().unwrap(),
+                self.conf.profile_name(),
                 COOKIE_FILE_SUFFIX
             ))
             .map(io::BufWriter::new)
@@ -619,7 +618,12 @@ impl Client {
                 .map(|i| i.en_name.clone())
                 .collect::<Vec<String>>()
         );
+        let api_endpoints = vpn_info
+            .iter()
+            .map(|vpn| format!("{}:{}", vpn.ip, vpn.api_port))
+            .collect();
         let mut vpn_addr = String::new();
+        let mut vpn_name = String::new();
         for vpn in vpn_info {
             if let Some(server_name) = self.conf.vpn_server_name.clone() {
                 if vpn.en_name != server_name {
@@ -627,25 +631,28 @@ impl Client {
                     continue;
                 }
             }
-            let mode = match vpn.protocol_mode {
-                1 => "tcp",
-                2 => "udp",
-                _ => "unknow protocol",
+            let protocol = match Protocol::try_from(vpn.protocol_mode) {
+                Ok(protocol) => protocol,
+                Err(e) => {
+                    log::warn!("skip {}: {}", vpn.en_name, e);
+                    continue;
+                }
             };
             log::info!(
                 "check if {} vpn {}:{} is available",
-                mode, &vpn.ip, &vpn.vpn_port
+                protocol, &vpn.ip, &vpn.vpn_port
             );
             vpn_addr = format!("{}:{}", &vpn.ip, vpn.vpn_port);
+            vpn_name = vpn.en_name.clone();
             if self.ping_vpn(vpn.ip, vpn.api_port).await {
                 log::info!("available");
-                match mode {
-                    "udp" => {
+                match protocol {
+                    Protocol::Udp => {
                         avalaible = true;
                         break;
                     }
-                    _ => {
-                        log::info!("we don't support {} wg for now", mode)
+                    Protocol::Tcp => {
+                        log::info!("we don't support {} wg for now", protocol)
                     }
                 };
             }
@@ -658,25 +665,63 @@ impl Client {
         let key = self.conf.public_key.clone().unwrap();
         log::info!("try to get wg conf from remote");
         let wg_info = self.fetch_peer_info(&key).await?;
-        let mtu = wg_info.setting.vpn_mtu;
-        let dns = wg_info.setting.vpn_dns;
+        let mtu = self.conf.mtu.map_or(wg_info.setting.vpn_mtu, Mtu::value);
+        let dns = [wg_info.setting.vpn_dns, wg_info.setting.vpn_dns_backup]
+            .into_iter()
+            .filter(|dns| !dns.is_empty())
+            .collect();
         let peer_key = wg_info.public_key;
         let public_key = self.conf.public_key.clone().unwrap();
         let private_key = self.conf.private_key.clone().unwrap();
-        let route = wg_info.setting.vpn_route_split;
+        // routes are parsed once here, bare addresses become host routes
+        let parse_routes = |routes: &[String]| {
+            route::parse_routes(routes).map_err(|e| Error::Error(e.to_string()))
+        };
+        let route = parse_routes(&wg_info.setting.vpn_route_split)?;
+        let routes = match &self.conf.routes {
+            Some(routes) => Some(parse_routes(routes)?),
+            None => None,
+        };
+        let exclude_route = parse_routes(&self.conf.exclude_route.clone().unwrap_or_default())?;
+        let profile = self.conf.profile(&vpn_name);
+        let mut extra_address = Vec::new();
+        if !wg_info.ipv6.is_empty() {
+            // ipv6 may come without prefix length
+            let addr = if wg_info.ipv6.contains('/') {
+                wg_info.ipv6
+            } else {
+                format!("{}/128", wg_info.ipv6)
+            };
+            extra_address.push(addr);
+        }
 
         // corplink config
         let wg_conf = WgConf {
             address: wg_info.ip,
             mask: wg_info.ip_mask.parse::<u32>().unwrap(),
-            peer_address: vpn_addr,
+            extra_address,
+            vpn_name,
             mtu,
             public_key,
             private_key,
-            peer_key,
-            route,
+            peers: vec![Peer {
+                preshared_key: self.conf.preshared_key.clone(),
+                keepalive: profile.keepalive,
+                fallback_endpoints: self.conf.fallback_endpoints.clone().unwrap_or_default(),
+                ..Peer::new(peer_key, vpn_addr, route)
+            }],
+            routes,
+            exclude_route,
+            manage_routes: self.conf.manage_routes.unwrap_or(true),
+            exclude_own_subnet: self.conf.exclude_own_subnet.unwrap_or(true),
+            replace: self.conf.replace_peers.unwrap_or(true),
+            roaming: self.conf.roaming.unwrap_or(true),
+            fwmark: WgConf::effective_fwmark(self.conf.fwmark, self.conf.route_table),
+            route_table: self.conf.route_table,
+            listen_port: self.conf.listen_port,
             dns,
-            protocol: 0,
+            protocol: Protocol::Udp,
+            api_endpoints,
         };
         Ok(wg_conf)
     }
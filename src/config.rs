@@ -1,10 +1,15 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
 use tokio::fs;
 
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 
 use crate::state::State;
-use crate::utils;
+use crate::{route, utils};
 
 const DEFAULT_DEVICE_NAME: &str = "DollarOS";
 const DEFAULT_INTERFACE_NAME: &str = "corplink";
@@ -22,6 +27,111 @@ pub const PLATFORM_DING_TALK: &str = "dingtalk";
 #[allow(dead_code)]
 pub const PLATFORM_AAD: &str = "aad";
 
+// protocol_mode of vpn servers
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl TryFrom<i32> for Protocol {
+    type Error = String;
+
+    fn try_from(mode: i32) -> Result<Self, Self::Error> {
+        match mode {
+            1 => Ok(Protocol::Tcp),
+            2 => Ok(Protocol::Udp),
+            _ => Err(format!("unknown protocol mode {}", mode)),
+        }
+    }
+}
+
+impl Protocol {
+    pub fn mode(&self) -> i32 {
+        match self {
+            Protocol::Tcp => 1,
+            Protocol::Udp => 2,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HandshakePolicy {
+    // connection is alive if any peer has a recent handshake
+    #[default]
+    Any,
+    // connection is alive only if all peers have recent handshakes
+    All,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StaleDetection {
+    // connection is dead if the last handshake is too old, unless the tunnel is idle
+    #[default]
+    Time,
+    // connection is alive as long as rx or tx bytes keep growing
+    Bytes,
+    // connection is alive only if it passes both checks
+    Both,
+}
+
+// what to do once the handshake is stale and refreshing the endpoints does not help
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeoutAction {
+    #[default]
+    Reconnect,
+    // exit with ETIMEDOUT, e.g. to let systemd restart it
+    Exit,
+    // run a command, then reconnect if it succeeds or exit if it fails
+    Hook(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MtuKeyword {
+    Auto,
+}
+
+// a fixed mtu, or auto to detect it from the path mtu to the endpoint
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(untagged)]
+pub enum Mtu {
+    Fixed(u32),
+    Keyword(MtuKeyword),
+}
+
+impl Mtu {
+    // 0 means detect automatically in wg conf
+    pub fn value(self) -> u32 {
+        match self {
+            Mtu::Fixed(mtu) => mtu,
+            Mtu::Keyword(MtuKeyword::Auto) => 0,
+        }
+    }
+}
+
+// tuning of a vpn server, fields not set fall back to the global ones
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ServerProfile {
+    pub keepalive: Option<u32>,
+    pub check_interval: Option<u64>,
+    pub check_timeout: Option<u64>,
+    pub max_reconnect: Option<u32>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub company_name: String,
@@ -33,13 +143,49 @@ pub struct Config {
     pub device_id: Option<String>,
     pub public_key: Option<String>,
     pub private_key: Option<String>,
+    pub preshared_key: Option<String>,
     pub server: Option<String>,
     pub interface_name: Option<String>,
+    pub profile_name: Option<String>,
     pub debug_wg: Option<bool>,
     #[serde(skip_serializing)]
     pub conf_file: Option<String>,
     pub state: Option<State>,
     pub vpn_server_name: Option<String>,
+    // keyed by the en_name of vpn servers
+    pub server_profiles: Option<HashMap<String, ServerProfile>>,
+    pub keepalive: Option<u32>,
+    pub roaming: Option<bool>,
+    pub check_interval: Option<u64>,
+    pub check_timeout: Option<u64>,
+    pub max_reconnect: Option<u32>,
+    pub on_timeout: Option<TimeoutAction>,
+    pub reconnect_cooldown: Option<u64>,
+    pub uapi_timeout: Option<u64>,
+    pub handshake_wait: Option<u64>,
+    pub exclude_route: Option<Vec<String>>,
+    pub routes: Option<Vec<String>>,
+    pub fallback_endpoints: Option<Vec<String>>,
+    pub manage_routes: Option<bool>,
+    pub exclude_own_subnet: Option<bool>,
+    pub replace_peers: Option<bool>,
+    pub mtu: Option<Mtu>,
+    pub fwmark: Option<u32>,
+    pub route_table: Option<u32>,
+    pub listen_port: Option<u16>,
+    pub use_vpn_dns: Option<bool>,
+    pub handshake_policy: Option<HandshakePolicy>,
+    pub stale_detection: Option<StaleDetection>,
+    pub degrade_ratio: Option<f64>,
+    pub quiet_check: Option<bool>,
+    pub status_socket: Option<String>,
+    pub metrics_listen: Option<String>,
+    pub address_file: Option<String>,
+    pub wg_quick: Option<String>,
+    pub post_up: Option<String>,
+    pub pre_down: Option<String>,
+    pub post_up_fatal: Option<bool>,
+    pub killswitch: Option<bool>,
 }
 
 impl fmt::Display for Config {
@@ -59,6 +205,11 @@ impl Config {
             .unwrap_or_else(|e| panic!("failed to parse config file {}: {}", file, e));
 
         conf.conf_file = Some(file.to_string());
+        // a killswitch that does nothing is worse than none
+        #[cfg(not(target_os = "linux"))]
+        if conf.killswitch == Some(true) {
+            panic!("killswitch in {} is only supported on linux", file);
+        }
         let mut update_conf = false;
         if conf.interface_name.is_none() {
             conf.interface_name = Some(DEFAULT_INTERFACE_NAME.to_string());
@@ -100,6 +251,31 @@ impl Config {
         conf
     }
 
+    // readable name of this config for logs and the cookie file,
+    // the interface name if not set
+    pub fn profile_name(&self) -> String {
+        self.profile_name
+            .clone()
+            .or_else(|| self.interface_name.clone())
+            .unwrap_or_default()
+    }
+
+    // profile of the vpn server merged with the global settings
+    pub fn profile(&self, vpn_name: &str) -> ServerProfile {
+        let profile = self
+            .server_profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(vpn_name))
+            .cloned()
+            .unwrap_or_default();
+        ServerProfile {
+            keepalive: profile.keepalive.or(self.keepalive),
+            check_interval: profile.check_interval.or(self.check_interval),
+            check_timeout: profile.check_timeout.or(self.check_timeout),
+            max_reconnect: profile.max_reconnect.or(self.max_reconnect),
+        }
+    }
+
     pub async fn save(&self) {
         let file = self.conf_file.as_ref().unwrap();
         let data = format!("{}", &self);
@@ -107,21 +283,394 @@ impl Config {
     }
 }
 
+// only the form is checked, the host is resolved when the uapi config is built
+fn check_endpoint(endpoint: &str) -> Result<(), String> {
+    // the port can not be told apart from an ipv6 address like 2001:db8::1:8000
+    if endpoint.parse::<IpAddr>().is_ok() || endpoint.ends_with(']') {
+        return Err("missing port".to_string());
+    }
+    let port = match endpoint.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => port,
+        _ => return Err("missing port".to_string()),
+    };
+    match port.parse::<u64>() {
+        Ok(port) if (1..=65535).contains(&port) => Ok(()),
+        Ok(port) => Err(format!("port {} is out of range 1-65535", port)),
+        Err(_) => Err(format!("port {} is not a number", port)),
+    }
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct Peer {
+    pub public_key: String,
+    // ip:port or host:port
+    pub endpoint: String,
+    // tried in order if there is no handshake through endpoint
+    pub fallback_endpoints: Vec<String>,
+    pub preshared_key: Option<String>,
+    pub allowed_ips: Vec<IpNet>,
+    pub keepalive: Option<u32>,
+}
+
+impl Peer {
+    pub fn new(public_key: String, endpoint: String, allowed_ips: Vec<IpNet>) -> Peer {
+        Peer {
+            public_key,
+            endpoint,
+            allowed_ips,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Serialize, Clone)]
 pub struct WgConf {
     // standard wg conf
     pub address: String,
     pub mask: u32,
-    pub peer_address: String,
+    // more addresses in cidr form, e.g. ipv6 address for dual stack
+    pub extra_address: Vec<String>,
+    // en_name of the vpn server, empty if not from corplink
+    pub vpn_name: String,
     pub mtu: u32,
     pub public_key: String,
     pub private_key: String,
-    pub peer_key: String,
-    pub route: Vec<String>,
+    pub peers: Vec<Peer>,
+    // system routes to install, the allowed ips of all peers if not set
+    pub routes: Option<Vec<IpNet>>,
+    // removed from allowed ips of all peers and routes
+    pub exclude_route: Vec<IpNet>,
+    // install system routes, allowed_ip is sent anyway
+    pub manage_routes: bool,
+    // carve the subnet of the interface address out of the system routes
+    pub exclude_own_subnet: bool,
+    // flush existing peers and allowed ips, or update them incrementally
+    pub replace: bool,
+    // no persistent keepalive is sent for a static endpoint
+    pub roaming: bool,
+    // mark on the packets of the tunnel for policy routing, linux only
+    pub fwmark: Option<u32>,
+    // routing table to install the routes into with ip rules, linux only
+    pub route_table: Option<u32>,
+    // udp port to listen on, picked by wg-corplink if not set
+    pub listen_port: Option<u16>,
 
     // extent confs
-    pub dns: String,
+    pub dns: Vec<String>,
 
     // corplink confs
-    pub protocol: i32,
+    pub protocol: Protocol,
+    // api addresses of all the vpn servers, for keep alive and pinging them on reconnect
+    pub api_endpoints: Vec<String>,
+}
+
+impl WgConf {
+    // the packets of the tunnel itself must bypass the routing table,
+    // so the table doubles as the fwmark if none is set
+    pub fn effective_fwmark(fwmark: Option<u32>, route_table: Option<u32>) -> Option<u32> {
+        fwmark.or(route_table)
+    }
+
+    // load a wg-quick config, so that it can be managed without corplink login
+    // keys only used by wg-quick scripts (PostUp, SaveConfig...) are ignored
+    pub fn from_wg_quick(path: &Path) -> io::Result<WgConf> {
+        let invalid = |msg: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), msg),
+            )
+        };
+        let data = std::fs::read_to_string(path)?;
+
+        let mut section = String::new();
+        let mut peers: Vec<Peer> = Vec::new();
+        let mut private_key = None;
+        let mut addresses = Vec::new();
+        let mut mtu = 0;
+        let mut fwmark = None;
+        let mut route_table = None;
+        let mut manage_routes = true;
+        let mut listen_port = None;
+        let mut dns = Vec::new();
+        for line in data.lines() {
+            let line = match line.split_once('#') {
+                Some((line, _)) => line,
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].trim().to_lowercase();
+                if section == "peer" {
+                    peers.push(Peer::default());
+                }
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim().to_lowercase(), value.trim().to_string()),
+                None => return Err(invalid(format!("invalid line {}", line))),
+            };
+            let list = || {
+                value
+                    .split(',')
+                    .map(|v| v.trim().to_string())
+                    .filter(|v| !v.is_empty())
+                    .collect::<Vec<String>>()
+            };
+            match (section.as_str(), key.as_str()) {
+                ("interface", "privatekey") => private_key = Some(value),
+                ("interface", "address") => {
+                    addresses.extend(list().iter().map(|addr| with_host_mask(addr)))
+                }
+                ("interface", "mtu") if value.eq_ignore_ascii_case("auto") => mtu = 0,
+                ("interface", "mtu") => {
+                    mtu = value
+                        .parse()
+                        .map_err(|_| invalid(format!("invalid mtu {}", value)))?
+                }
+                ("interface", "listenport") => {
+                    let port = value
+                        .parse()
+                        .map_err(|_| invalid(format!("invalid listen port {}", value)))?;
+                    listen_port = Some(port);
+                }
+                ("interface", "fwmark") if value.eq_ignore_ascii_case("off") => fwmark = None,
+                ("interface", "fwmark") => {
+                    // hex is accepted by wg-quick as well
+                    let mark = match value.strip_prefix("0x") {
+                        Some(hex) => u32::from_str_radix(hex, 16),
+                        None => value.parse(),
+                    };
+                    let mark = mark.map_err(|_| invalid(format!("invalid fwmark {}", value)))?;
+                    // 0 is off as well
+                    fwmark = Some(mark).filter(|mark| *mark != 0);
+                }
+                // auto is the main table, like what it means for wg-quick
+                ("interface", "table") if value.eq_ignore_ascii_case("auto") => route_table = None,
+                ("interface", "table") if value.eq_ignore_ascii_case("off") => {
+                    manage_routes = false
+                }
+                ("interface", "table") => {
+                    let table = value
+                        .parse()
+                        .map_err(|_| invalid(format!("invalid table {}", value)))?;
+                    route_table = Some(table);
+                }
+                // search domains are not supported
+                ("interface", "dns") => dns.extend(
+                    list()
+                        .into_iter()
+                        .filter(|server| server.parse::<IpAddr>().is_ok()),
+                ),
+                // the section is only set to peer after a peer is pushed
+                ("peer", "publickey") => peers.last_mut().unwrap().public_key = value,
+                ("peer", "presharedkey") => peers.last_mut().unwrap().preshared_key = Some(value),
+                ("peer", "endpoint") => peers.last_mut().unwrap().endpoint = value,
+                ("peer", "allowedips") => {
+                    let routes =
+                        route::parse_routes(&list()).map_err(|e| invalid(e.to_string()))?;
+                    peers.last_mut().unwrap().allowed_ips.extend(routes);
+                }
+                ("peer", "persistentkeepalive") => {
+                    let interval = value
+                        .parse()
+                        .map_err(|_| invalid(format!("invalid persistent keepalive {}", value)))?;
+                    peers.last_mut().unwrap().keepalive = Some(interval);
+                }
+                _ => log::debug!("ignore {} of [{}] in {}", key, section, path.display()),
+            }
+        }
+
+        let private_key = private_key.ok_or_else(|| invalid("no PrivateKey".to_string()))?;
+        if !utils::is_valid_wg_key(&private_key) {
+            return Err(invalid(
+                "PrivateKey is not a valid WireGuard key".to_string(),
+            ));
+        }
+        let public_key =
+            utils::gen_public_key_from_private(&private_key).map_err(|e| invalid(e.to_string()))?;
+        if addresses.is_empty() {
+            return Err(invalid("no Address".to_string()));
+        }
+        if peers.is_empty() {
+            return Err(invalid("no [Peer]".to_string()));
+        }
+        for (i, peer) in peers.iter_mut().enumerate() {
+            if peer.public_key.is_empty() {
+                return Err(invalid(format!("no PublicKey of peer {}", i)));
+            }
+            if peer.endpoint.is_empty() {
+                return Err(invalid(format!("no Endpoint of peer {}", i)));
+            }
+            // wg-quick does not send keepalive unless PersistentKeepalive is set
+            peer.keepalive.get_or_insert(0);
+        }
+        let first = addresses.remove(0);
+        // always there after with_host_mask
+        let (address, mask) = first.split_once('/').unwrap();
+        let address = address.to_string();
+        let mask = mask
+            .parse()
+            .map_err(|_| invalid(format!("invalid address {}", first)))?;
+
+        log::info!("protocol is not set by wg-quick config, default to udp");
+        Ok(WgConf {
+            address,
+            mask,
+            extra_address: addresses,
+            vpn_name: String::new(),
+            mtu,
+            public_key,
+            private_key,
+            peers,
+            routes: None,
+            exclude_route: Vec::new(),
+            manage_routes,
+            exclude_own_subnet: true,
+            replace: true,
+            roaming: true,
+            fwmark: WgConf::effective_fwmark(fwmark, route_table),
+            route_table,
+            listen_port,
+            dns,
+            protocol: Protocol::Udp,
+            api_endpoints: Vec::new(),
+        })
+    }
+
+    // check all the fields at once, so that every problem is reported together
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        for (field, key) in [
+            ("public_key", &self.public_key),
+            ("private_key", &self.private_key),
+        ] {
+            if !utils::is_valid_wg_key(key) {
+                problems.push(format!("{} is not a valid 32-byte WireGuard key", field));
+            }
+        }
+        if self.peers.is_empty() {
+            problems.push("no peer is configured".to_string());
+        }
+        for (i, peer) in self.peers.iter().enumerate() {
+            if !utils::is_valid_wg_key(&peer.public_key) {
+                problems.push(format!(
+                    "public_key of peer {} is not a valid 32-byte WireGuard key",
+                    i
+                ));
+            }
+            if let Some(key) = &peer.preshared_key {
+                if !utils::is_valid_wg_key(key) {
+                    problems.push(format!(
+                        "preshared_key of peer {} is not a valid 32-byte WireGuard key",
+                        i
+                    ));
+                }
+            }
+            for endpoint in std::iter::once(&peer.endpoint).chain(&peer.fallback_endpoints) {
+                if let Err(e) = check_endpoint(endpoint) {
+                    problems.push(format!(
+                        "invalid endpoint '{}' of peer {}: {}",
+                        endpoint, i, e
+                    ));
+                }
+            }
+        }
+        match self.address.parse::<IpAddr>() {
+            Ok(addr) => {
+                let (family, max_mask) = if addr.is_ipv4() {
+                    ("IPv4", 32)
+                } else {
+                    ("IPv6", 128)
+                };
+                if self.mask > max_mask {
+                    problems.push(format!(
+                        "mask {} invalid for {} address {}, it should be in 0-{}",
+                        self.mask, family, self.address, max_mask
+                    ));
+                }
+            }
+            Err(_) => problems.push(format!("address {} is not a valid ip", self.address)),
+        }
+        for addr in &self.extra_address {
+            if addr.parse::<IpNet>().is_err() {
+                problems.push(format!("address {} is not a valid ip with mask", addr));
+            }
+        }
+        if self.fwmark == Some(0) {
+            problems.push("fwmark must not be 0, leave it unset to disable it".to_string());
+        }
+        if let Some(table) = self.route_table {
+            // 0 is unspecified, 253-255 are the default, main and local tables
+            if table == 0 || (253..=255).contains(&table) {
+                problems.push(format!("route_table {} is reserved", table));
+            }
+            if self.fwmark.is_none() {
+                problems.push("fwmark is required by route_table".to_string());
+            }
+        }
+        // 0 means detect automatically
+        if self.mtu != 0 && !(576..=65535).contains(&self.mtu) {
+            problems.push(format!("mtu {} is out of range 576-65535", self.mtu));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+}
+
+// wg-quick takes an address without a mask as a single host
+fn with_host_mask(address: &str) -> String {
+    if address.contains('/') {
+        address.to_string()
+    } else if address.contains(':') {
+        format!("{}/128", address)
+    } else {
+        format!("{}/32", address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const PEER_KEY: &str = "ICEiIyQlJicoKSorLC0uLzAxMjM0NTY3ODk6Ozw9Pj8=";
+
+    fn load_wg_quick(name: &str, interface: &str) -> io::Result<WgConf> {
+        let path =
+            std::env::temp_dir().join(format!("corplink-{}-{}.conf", name, std::process::id()));
+        let data = format!(
+            "[Interface]\nPrivateKey = {PRIVATE_KEY}\n{interface}\n\n\
+             [Peer]\nPublicKey = {PEER_KEY}\nEndpoint = 1.2.3.4:51820\nAllowedIPs = 0.0.0.0/0\n"
+        );
+        std::fs::write(&path, data)?;
+        let conf = WgConf::from_wg_quick(&path);
+        _ = std::fs::remove_file(&path);
+        conf
+    }
+
+    #[test]
+    fn test_wg_quick_addresses_without_mask() {
+        let conf = load_wg_quick("addresses", "Address = 10.0.0.2, fd00::2").unwrap();
+        assert_eq!((conf.address.as_str(), conf.mask), ("10.0.0.2", 32));
+        assert_eq!(conf.extra_address, ["fd00::2/128"]);
+        conf.validate().unwrap();
+    }
+
+    #[test]
+    fn test_wg_quick_addresses_with_mask() {
+        let conf = load_wg_quick(
+            "masks",
+            "Address = 10.0.0.2/24\nAddress = fd00::2/64, 10.0.1.2",
+        )
+        .unwrap();
+        assert_eq!((conf.address.as_str(), conf.mask), ("10.0.0.2", 24));
+        assert_eq!(conf.extra_address, ["fd00::2/64", "10.0.1.2/32"]);
+        conf.validate().unwrap();
+    }
 }
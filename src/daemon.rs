@@ -0,0 +1,83 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::sync::OnceLock;
+
+static PIDFILE: OnceLock<String> = OnceLock::new();
+
+extern "C" fn remove_pidfile() {
+    if let Some(pidfile) = PIDFILE.get() {
+        _ = fs::remove_file(pidfile);
+    }
+}
+
+fn fork() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        // child
+        0 => Ok(()),
+        _ => process::exit(0),
+    }
+}
+
+fn redirect(fd: i32, path: &str, write: bool) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .read(!write)
+        .append(write)
+        .create(write)
+        .open(path)?;
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// check if the daemon of the pidfile is still alive
+fn is_running(pidfile: &str) -> bool {
+    let pid = match fs::read_to_string(pidfile) {
+        Ok(pid) => pid,
+        Err(_) => return false,
+    };
+    match pid.trim().parse::<i32>() {
+        Ok(pid) => unsafe { libc::kill(pid, 0) == 0 },
+        Err(_) => false,
+    }
+}
+
+// detach from the terminal, must be called before the tokio runtime starts
+// the working directory is kept, so that relative config path still works
+pub fn daemonize(log_file: Option<&str>, pidfile: Option<&str>) -> io::Result<()> {
+    if let Some(pidfile) = pidfile {
+        if is_running(pidfile) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("another daemon of {} is running", pidfile),
+            ));
+        }
+    }
+    // flush the logs before fork, or they will be written twice
+    io::stdout().flush()?;
+    fork()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // fork again so that the daemon can never acquire a terminal
+    fork()?;
+
+    let log_file = log_file.unwrap_or("/dev/null");
+    redirect(libc::STDIN_FILENO, "/dev/null", false)?;
+    redirect(libc::STDOUT_FILENO, log_file, true)?;
+    redirect(libc::STDERR_FILENO, log_file, true)?;
+
+    if let Some(pidfile) = pidfile {
+        fs::write(pidfile, format!("{}\n", process::id()))?;
+        PIDFILE.get_or_init(|| pidfile.to_string());
+        // exit() is called from many places, remove the pidfile on all of them
+        unsafe {
+            libc::atexit(remove_pidfile);
+        }
+    }
+    log::info!("running as daemon with pid {}", process::id());
+    Ok(())
+}
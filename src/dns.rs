@@ -0,0 +1,170 @@
+use std::io;
+
+use crate::utils::{self, run_cmd};
+
+#[derive(Debug)]
+enum Backend {
+    #[cfg(target_os = "linux")]
+    Resolvectl,
+    #[cfg(target_os = "linux")]
+    Resolvconf,
+    #[cfg(windows)]
+    Netsh,
+}
+
+// dns servers pushed to the system resolver for an interface,
+// they are scoped to the interface so that restoring only needs to
+// revert the interface config
+pub struct SystemDns {
+    interface: String,
+    backend: Option<Backend>,
+}
+
+fn detect_backend() -> Option<Backend> {
+    #[cfg(target_os = "linux")]
+    {
+        if utils::cmd_exist("resolvectl") {
+            return Some(Backend::Resolvectl);
+        }
+        if utils::cmd_exist("resolvconf") {
+            return Some(Backend::Resolvconf);
+        }
+    }
+    #[cfg(windows)]
+    if utils::cmd_exist("netsh") {
+        return Some(Backend::Netsh);
+    }
+    None
+}
+
+async fn set_dns(backend: &Backend, interface: &str, servers: &[String]) -> io::Result<()> {
+    match backend {
+        #[cfg(target_os = "linux")]
+        Backend::Resolvectl => {
+            let mut args = vec!["dns", interface];
+            args.extend(servers.iter().map(|s| s.as_str()));
+            run_cmd("resolvectl", &args, None).await
+        }
+        #[cfg(target_os = "linux")]
+        Backend::Resolvconf => {
+            let input: String = servers
+                .iter()
+                .map(|s| format!("nameserver {s}\n"))
+                .collect();
+            run_cmd(
+                "resolvconf",
+                &["-a", interface, "-m", "0", "-x"],
+                Some(&input),
+            )
+            .await
+        }
+        #[cfg(windows)]
+        Backend::Netsh => {
+            for (i, server) in servers.iter().enumerate() {
+                let name = format!("name={interface}");
+                let address = format!("address={server}");
+                let index = format!("index={}", i + 1);
+                let family = if server.contains(':') { "ipv6" } else { "ipv4" };
+                let args = match i {
+                    0 => vec![
+                        "interface",
+                        family,
+                        "set",
+                        "dnsservers",
+                        &name,
+                        "source=static",
+                        &address,
+                        "validate=no",
+                    ],
+                    _ => vec![
+                        "interface",
+                        family,
+                        "add",
+                        "dnsservers",
+                        &name,
+                        &address,
+                        &index,
+                        "validate=no",
+                    ],
+                };
+                run_cmd("netsh", &args, None).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn revert_dns(backend: &Backend, interface: &str) -> io::Result<()> {
+    match backend {
+        #[cfg(target_os = "linux")]
+        Backend::Resolvectl => run_cmd("resolvectl", &["revert", interface], None).await,
+        #[cfg(target_os = "linux")]
+        Backend::Resolvconf => run_cmd("resolvconf", &["-d", interface, "-f"], None).await,
+        #[cfg(windows)]
+        Backend::Netsh => {
+            let name = format!("name={interface}");
+            for family in ["ipv4", "ipv6"] {
+                run_cmd(
+                    "netsh",
+                    &[
+                        "interface",
+                        family,
+                        "set",
+                        "dnsservers",
+                        &name,
+                        "source=dhcp",
+                    ],
+                    None,
+                )
+                .await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl SystemDns {
+    // failing to set dns is not fatal, the tunnel still works with ip
+    pub async fn apply(interface: &str, servers: &[String]) -> SystemDns {
+        let mut dns = SystemDns {
+            interface: interface.to_string(),
+            backend: None,
+        };
+        if servers.is_empty() {
+            log::info!("no dns server provided for {}", interface);
+            return dns;
+        }
+        let backend = match detect_backend() {
+            Some(backend) => backend,
+            None => {
+                log::warn!(
+                    "no supported resolver tool found, skip setting dns for {}",
+                    interface
+                );
+                return dns;
+            }
+        };
+        match set_dns(&backend, interface, servers).await {
+            Ok(_) => {
+                log::info!(
+                    "set dns of {} to {:?} with {:?}",
+                    interface,
+                    servers,
+                    backend
+                );
+                dns.backend = Some(backend);
+            }
+            Err(e) => log::warn!("failed to set dns of {}: {}", interface, e),
+        }
+        dns
+    }
+
+    pub async fn restore(&mut self) {
+        if let Some(backend) = self.backend.take() {
+            match revert_dns(&backend, &self.interface).await {
+                Ok(_) => log::info!("restored dns of {}", self.interface),
+                Err(e) => log::warn!("failed to restore dns of {}: {}", self.interface, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::broadcast;
+
+// events are dropped for subscribers lagging behind more than this
+const EVENT_CAPACITY: usize = 16;
+
+#[derive(Clone, Debug)]
+pub enum EventKind {
+    Connecting,
+    Connected {
+        interface: String,
+    },
+    HandshakeDegrading {
+        interface: String,
+        elapsed: Duration,
+        timeout: Duration,
+    },
+    HandshakeStale {
+        interface: String,
+        elapsed: Duration,
+    },
+    Reconnecting {
+        attempt: u32,
+        backoff: Duration,
+    },
+    Disconnected {
+        interface: String,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub time: SystemTime,
+    pub kind: EventKind,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = chrono::DateTime::<chrono::Local>::from(self.time);
+        write!(f, "[{}] ", time.to_rfc3339())?;
+        match &self.kind {
+            EventKind::Connecting => write!(f, "connecting"),
+            EventKind::Connected { interface } => write!(f, "{} connected", interface),
+            EventKind::HandshakeDegrading {
+                interface,
+                elapsed,
+                timeout,
+            } => write!(
+                f,
+                "handshake of {} is degrading, {}s of {}s",
+                interface,
+                elapsed.as_secs(),
+                timeout.as_secs()
+            ),
+            EventKind::HandshakeStale { interface, elapsed } => write!(
+                f,
+                "handshake of {} is stale for {}s",
+                interface,
+                elapsed.as_secs()
+            ),
+            EventKind::Reconnecting { attempt, backoff } => {
+                write!(f, "reconnect attempt {} in {}s", attempt, backoff.as_secs())
+            }
+            EventKind::Disconnected { interface } => write!(f, "{} disconnected", interface),
+        }
+    }
+}
+
+// state transitions of the tunnel, for frontends like a tray app
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events::new()
+    }
+}
+
+impl Events {
+    pub fn new() -> Events {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Events { sender }
+    }
+
+    pub fn publish(&self, kind: EventKind) {
+        let event = Event {
+            time: SystemTime::now(),
+            kind,
+        };
+        // no subscriber is not an error
+        _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,44 @@
+use std::io;
+use std::process::Stdio;
+
+use tokio::process::Command;
+
+use crate::config::WgConf;
+
+// run a user command like PostUp/PreDown of wg-quick through the shell,
+// the interface and its address are passed as env vars
+pub async fn run_hook(hook: &str, cmd: &str, interface: &str, conf: &WgConf) -> io::Result<()> {
+    log::info!("run {} hook of {}: {}", hook, interface, cmd);
+    #[cfg(unix)]
+    let mut command = Command::new("sh");
+    #[cfg(unix)]
+    command.arg("-c");
+    #[cfg(windows)]
+    let mut command = Command::new("cmd");
+    #[cfg(windows)]
+    command.arg("/C");
+    let output = command
+        .arg(cmd)
+        .env("CORPLINK_INTERFACE", interface)
+        .env(
+            "CORPLINK_ADDRESS",
+            format!("{}/{}", conf.address, conf.mask),
+        )
+        .env("CORPLINK_VPN_NAME", &conf.vpn_name)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        log::info!("{} hook: {}", hook, line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        log::warn!("{} hook: {}", hook, line);
+    }
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} hook exits with {}",
+            hook, output.status
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,214 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use reqwest::Url;
+use tokio::net::lookup_host;
+
+use crate::config::WgConf;
+#[cfg(target_os = "linux")]
+use crate::utils::run_cmd;
+
+#[cfg(target_os = "linux")]
+const TABLE: &str = "corplink_killswitch";
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+// firewall rules only letting traffic out through the tunnel,
+// they are kept across reconnects so that nothing leaks while the tunnel is down
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct Killswitch {
+    // the corplink server is still needed to reconnect,
+    // the api ports of the vpn servers are added by each session
+    control: Vec<SocketAddr>,
+    // hostnames of the server and endpoints are resolved again on reconnect,
+    // so dns is allowed to the resolvers the system had before connecting
+    resolvers: Vec<IpAddr>,
+    active: bool,
+}
+
+async fn resolve(host: &str) -> Vec<SocketAddr> {
+    match lookup_host(host).await {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+            log::warn!("failed to resolve {} for killswitch: {}", host, e);
+            Vec::new()
+        }
+    }
+}
+
+// nameserver lines of resolv.conf, a resolver on loopback like systemd-resolved
+// is allowed through lo anyway
+fn parse_resolvers(content: &str) -> Vec<IpAddr> {
+    content
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|addr| {
+            // zone index of a link local address is not accepted by IpAddr
+            let addr = addr.trim().split('%').next()?;
+            addr.parse().ok()
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn nft_rules(
+    interface: &str,
+    endpoints: &[SocketAddr],
+    control: &[SocketAddr],
+    resolvers: &[IpAddr],
+) -> String {
+    let family = |addr: &IpAddr| if addr.is_ipv4() { "ip" } else { "ip6" };
+    let mut rules = vec![
+        "oifname \"lo\" accept".to_string(),
+        format!("oifname \"{interface}\" accept"),
+        // dhcp and neighbor discovery keep the physical link working
+        "udp sport 68 udp dport 67 accept".to_string(),
+        "icmpv6 type { nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert } accept"
+            .to_string(),
+    ];
+    for addr in resolvers {
+        rules.push(format!(
+            "{} daddr {} meta l4proto {{ tcp, udp }} th dport 53 accept",
+            family(addr),
+            addr
+        ));
+    }
+    for addr in endpoints {
+        rules.push(format!(
+            "{} daddr {} udp dport {} accept",
+            family(&addr.ip()),
+            addr.ip(),
+            addr.port()
+        ));
+    }
+    for addr in control {
+        rules.push(format!(
+            "{} daddr {} tcp dport {} accept",
+            family(&addr.ip()),
+            addr.ip(),
+            addr.port()
+        ));
+    }
+    // the table is created before deleting it, so that the first apply does not fail,
+    // and the whole script is applied atomically by nft
+    let mut script = format!("table inet {TABLE}\ndelete table inet {TABLE}\n");
+    script.push_str(&format!("table inet {TABLE} {{\n"));
+    script.push_str("    chain output {\n");
+    script.push_str("        type filter hook output priority 0; policy drop;\n");
+    for rule in rules {
+        script.push_str(&format!("        {rule}\n"));
+    }
+    script.push_str("    }\n}\n");
+    script
+}
+
+impl Killswitch {
+    pub async fn new(server: Option<&str>) -> Killswitch {
+        let mut control = Vec::new();
+        if let Some(url) = server.and_then(|server| Url::from_str(server).ok()) {
+            if let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) {
+                control = resolve(&format!("{host}:{port}")).await;
+            }
+        }
+        let resolvers = match std::fs::read_to_string(RESOLV_CONF) {
+            Ok(content) => parse_resolvers(&content),
+            Err(e) => {
+                log::warn!("failed to read {} for killswitch: {}", RESOLV_CONF, e);
+                Vec::new()
+            }
+        };
+        Killswitch {
+            control,
+            resolvers,
+            active: false,
+        }
+    }
+
+    // replace the rules of the previous session with the ones of the new interface
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    pub async fn apply(&mut self, interface: &str, conf: &WgConf) -> io::Result<()> {
+        let mut endpoints = Vec::new();
+        for peer in &conf.peers {
+            // the fallbacks are allowed as well, so that they can be tried on reconnect
+            for endpoint in std::iter::once(&peer.endpoint).chain(&peer.fallback_endpoints) {
+                endpoints.extend(resolve(endpoint).await);
+            }
+        }
+        // keep alive, disconnect and the ping on reconnect go to the api port of vpn servers
+        let mut control = self.control.clone();
+        for endpoint in &conf.api_endpoints {
+            control.extend(resolve(endpoint).await);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let rules = nft_rules(interface, &endpoints, &control, &self.resolvers);
+            run_cmd("nft", &["-f", "-"], Some(&rules)).await?;
+            log::info!("killswitch only allows traffic through {}", interface);
+            self.active = true;
+            Ok(())
+        }
+        // rejected by the config already
+        #[cfg(not(target_os = "linux"))]
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "killswitch is only supported on linux",
+        ))
+    }
+
+    // only called on a clean shutdown, the rules stay if corplink-rs crashes
+    pub async fn remove(&mut self) {
+        if !self.active {
+            return;
+        }
+        self.active = false;
+        #[cfg(target_os = "linux")]
+        {
+            let rules = format!("delete table inet {TABLE}\n");
+            match run_cmd("nft", &["-f", "-"], Some(&rules)).await {
+                Ok(_) => log::info!("killswitch removed"),
+                Err(e) => log::warn!("failed to remove killswitch: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nft_rules() {
+        let endpoints = ["1.2.3.4:51820".parse().unwrap()];
+        let control = [
+            "5.6.7.8:443".parse().unwrap(),
+            "1.2.3.4:8443".parse().unwrap(),
+            "[2001:db8::1]:8443".parse().unwrap(),
+        ];
+        let resolvers = ["192.168.1.1".parse().unwrap()];
+        let rules = nft_rules("corplink", &endpoints, &control, &resolvers);
+        assert!(rules.contains("type filter hook output priority 0; policy drop;"));
+        assert!(rules.contains("oifname \"corplink\" accept"));
+        assert!(rules.contains("ip daddr 1.2.3.4 udp dport 51820 accept"));
+        assert!(rules.contains("ip daddr 5.6.7.8 tcp dport 443 accept"));
+        assert!(rules.contains("ip daddr 1.2.3.4 tcp dport 8443 accept"));
+        assert!(rules.contains("ip6 daddr 2001:db8::1 tcp dport 8443 accept"));
+        assert!(rules.contains("ip daddr 192.168.1.1 meta l4proto { tcp, udp } th dport 53 accept"));
+        // dns is not allowed to any other address
+        assert!(!rules.contains("        meta l4proto"));
+    }
+
+    #[test]
+    fn test_parse_resolvers() {
+        let content = "# generated\nnameserver 192.168.1.1\nnameserver fe80::1%eth0\n\
+                       search lan\nnameserver bogus\n";
+        let resolvers = parse_resolvers(content);
+        assert_eq!(
+            resolvers,
+            [
+                "192.168.1.1".parse::<IpAddr>().unwrap(),
+                "fe80::1".parse().unwrap()
+            ]
+        );
+    }
+}
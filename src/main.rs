@@ -1,8 +1,22 @@
 mod api;
 mod client;
 mod config;
+#[cfg(unix)]
+mod daemon;
+mod dns;
+mod event;
+mod hook;
+mod killswitch;
+mod metrics;
+mod mtu;
+mod reconnect;
 mod resp;
+mod route;
 mod state;
+#[cfg(unix)]
+mod status;
+#[cfg(target_os = "linux")]
+mod table;
 mod template;
 mod totp;
 mod utils;
@@ -12,63 +26,171 @@ mod wg;
 use is_elevated;
 
 use std::env;
+use std::future;
+use std::path::Path;
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use env_logger::{Builder, Env, Target};
+use tokio::signal;
+use tokio::sync::{broadcast, oneshot, watch};
 
 use client::Client;
 use config::{Config, WgConf};
 
-fn print_usage_and_exit(name: &str, conf: &str) {
-    println!("usage:\n\t{} {}", name, conf);
+fn print_usage_and_exit(name: &str, conf: &str) -> ! {
+    #[cfg(unix)]
+    println!(
+        "usage:\n\t{} [--check] [--dry-run] [--no-root] [--daemon [--pidfile file] [--log-file file]] {}",
+        name, conf
+    );
+    #[cfg(windows)]
+    println!(
+        "usage:\n\t{} [--check] [--dry-run] [--no-root] {}",
+        name, conf
+    );
+    println!("options:");
+    println!("\t--check\t\tcheck the config and environment without connecting");
+    println!("\t--dry-run\tprint the uapi config instead of starting wg-corplink");
+    println!("\t--no-root\tdo not escalate to root, tun creation must be allowed already");
+    #[cfg(unix)]
+    {
+        println!("\t--daemon\trun in background");
+        println!("\t--pidfile\twrite pid of the daemon to file, removed on exit");
+        println!("\t--log-file\tappend logs of the daemon to file, default is /dev/null");
+    }
     exit(1);
 }
 
-fn parse_arg() -> String {
+struct Args {
+    conf_file: String,
+    check: bool,
+    dry_run: bool,
+    no_root: bool,
+    daemon: bool,
+    pidfile: Option<String>,
+    log_file: Option<String>,
+}
+
+fn parse_arg() -> Args {
     let mut conf_file = String::from("config.json");
+    let mut check = false;
+    let mut dry_run = false;
+    let mut no_root = false;
+    let mut daemon = false;
+    let mut pidfile = None;
+    let mut log_file = None;
     let mut args = env::args();
     // pop name
     let name = args.next().unwrap();
-    match args.len() {
-        0 => {}
-        1 => {
-            // pop arg
-            let arg = args.next().unwrap();
-            match arg.as_str() {
-                "-h" | "--help" => {
-                    print_usage_and_exit(&name, &conf_file);
+    let mut has_conf_file = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print_usage_and_exit(&name, &conf_file);
+            }
+            "--check" => {
+                check = true;
+            }
+            "--dry-run" => {
+                dry_run = true;
+            }
+            "--no-root" => {
+                no_root = true;
+            }
+            // there is no daemon mode on windows
+            #[cfg(windows)]
+            "--daemon" | "--pidfile" | "--log-file" => {
+                println!("{} is only supported on unix", arg);
+                print_usage_and_exit(&name, &conf_file);
+            }
+            "--daemon" => {
+                daemon = true;
+            }
+            "--pidfile" | "--log-file" => {
+                let value = match args.next() {
+                    Some(value) => value,
+                    None => print_usage_and_exit(&name, &conf_file),
+                };
+                if arg == "--pidfile" {
+                    pidfile = Some(value);
+                } else {
+                    log_file = Some(value);
                 }
-                _ => {
-                    conf_file = arg;
+            }
+            _ => {
+                if has_conf_file {
+                    print_usage_and_exit(&name, &conf_file);
                 }
+                conf_file = arg;
+                has_conf_file = true;
             }
         }
-        _ => {
-            print_usage_and_exit(&name, &conf_file);
-        }
     }
-    conf_file
+    Args {
+        conf_file,
+        check,
+        dry_run,
+        no_root,
+        daemon,
+        pidfile,
+        log_file,
+    }
 }
 
 pub const EPERM: i32 = 1;
 pub const ENOENT: i32 = 2;
 pub const ETIMEDOUT: i32 = 110;
 
-#[tokio::main]
-async fn main() {
+fn main() {
     Builder::from_env(Env::default().default_filter_or("info"))
         .target(Target::Stdout)
         .init();
 
     print_version();
-    check_previlige();
+    let args = parse_arg();
+    if args.check {
+        exit(if preflight(&args) { 0 } else { EPERM });
+    }
+    if args.no_root {
+        log::warn!("run without root, routes and dns may fail to apply");
+    } else if !args.dry_run {
+        check_previlige();
+    }
+    if args.daemon && !args.dry_run {
+        if needs_input(&args.conf_file) {
+            log::error!(
+                "code is not set in {}, the 2fa code can not be typed in as a daemon, \
+                 run in foreground once to login and save it first",
+                args.conf_file
+            );
+            exit(EPERM);
+        }
+        // fork is only safe before the runtime spawns its threads
+        #[cfg(unix)]
+        if let Err(e) = daemon::daemonize(args.log_file.as_deref(), args.pidfile.as_deref()) {
+            log::error!("failed to run as daemon: {}", e);
+            exit(EPERM);
+        }
+    }
 
-    let conf_file = parse_arg();
-    let mut conf = Config::from_file(&conf_file).await;
-    let name = conf.interface_name.clone().unwrap();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(run(args));
+}
 
-    match conf.server {
-        Some(_) => {}
-        None => match client::get_company_url(conf.company_name.as_str()).await {
+async fn run(args: Args) {
+    let mut conf = Config::from_file(&args.conf_file).await;
+    let interface_name = conf.interface_name.clone().unwrap();
+    let profile_name = conf.profile_name();
+
+    // a wg-quick config is managed without corplink login
+    let wg_quick = conf.wg_quick.clone();
+    if wg_quick.is_none() && conf.server.is_none() {
+        match client::get_company_url(conf.company_name.as_str()).await {
             Ok(resp) => {
                 log::info!(
                     "company name is {}(zh)/{}(en) server is {}",
@@ -84,92 +206,556 @@ async fn main() {
                 );
                 exit(EPERM);
             }
-        },
+        }
     }
 
     let with_wg_log = conf.debug_wg.unwrap_or_default();
-    let mut c = Client::new(conf).unwrap();
-    let mut logout_retry = true;
-    let wg_conf: Option<WgConf>;
+    let use_vpn_dns = conf.use_vpn_dns.unwrap_or_default();
+    let status_socket = conf.status_socket.clone();
+    let metrics_listen = conf.metrics_listen.clone();
+    let address_file = conf.address_file.clone();
+    let post_up = conf.post_up.clone();
+    let pre_down = conf.pre_down.clone();
+    let post_up_fatal = conf.post_up_fatal.unwrap_or(true);
+    let on_timeout = conf.on_timeout.clone().unwrap_or_default();
+    let use_killswitch = conf.killswitch.unwrap_or_default();
+    let use_route_table = conf.route_table.is_some();
+    let uapi_timeout = Duration::from_secs(conf.uapi_timeout.unwrap_or(wg::DEFAULT_UAPI_TIMEOUT));
+    let handshake_wait = Duration::from_secs(conf.handshake_wait.unwrap_or_default());
+    let reconnect_cooldown = conf
+        .reconnect_cooldown
+        .unwrap_or(reconnect::DEFAULT_COOLDOWN);
+    #[cfg(not(unix))]
+    if status_socket.is_some() {
+        log::warn!("status_socket is only supported on unix");
+    }
+    if !args.dry_run {
+        let missing = check_dependencies(use_vpn_dns, use_killswitch, use_route_table);
+        if !missing.is_empty() {
+            for dep in &missing {
+                log::error!("missing dependency: {}", dep);
+            }
+            exit(ENOENT);
+        }
+    }
+    // tuning is resolved per session from the profile of the connected server
+    let profile_conf = conf.clone();
+    let mut killswitch = None;
+    if use_killswitch && !args.dry_run {
+        killswitch = Some(killswitch::Killswitch::new(conf.server.as_deref()).await);
+    }
+    let mut c = match wg_quick {
+        Some(_) => None,
+        None => Some(Client::new(conf).unwrap()),
+    };
 
-    loop {
-        if c.need_login() {
-            log::info!("not login yet, try to login");
-            c.login().await.unwrap();
-            log::info!("login success");
+    if args.dry_run {
+        let wg_conf = match load_wg_conf(&mut c, wg_quick.as_deref()).await {
+            Some(wg_conf) => wg_conf,
+            None => exit(EPERM),
+        };
+        let mut exit_code = 0;
+        match wg::prepare_uapi_config(&wg_conf).await {
+            Ok(buff) => println!("{}", buff),
+            Err(err) => {
+                log::error!(
+                    "failed to build uapi config for {}: {}",
+                    interface_name,
+                    err
+                );
+                exit_code = EPERM;
+            }
         }
-        log::info!("try to connect");
-        match c.connect_vpn().await {
-            Ok(conf) => {
-                wg_conf = Some(conf);
-                break;
+        if let Some(c) = &mut c {
+            match c.disconnect_vpn(&wg_conf).await {
+                Ok(_) => {}
+                Err(e) => log::warn!("failed to disconnect vpn: {}", e),
+            };
+        }
+        exit(exit_code);
+    }
+
+    let events = event::Events::new();
+    let mut event_receiver = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match event_receiver.recv().await {
+                Ok(event) => log::debug!("event: {}", event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    log::debug!("{} event(s) dropped", n)
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
             }
-            Err(e) => {
-                if logout_retry && e.to_string().contains("logout") {
-                    // e contains detail message, so just print it out
-                    log::warn!("{}", e);
-                    logout_retry = false;
-                    continue;
-                } else {
-                    panic!("{}", e);
+        }
+    });
+
+    // installed before the first connection, so that a signal while connecting
+    // goes through the shutdown of the session instead of killing the process
+    let (stop_sender, mut stop) = watch::channel(false);
+    tokio::spawn(async move {
+        wait_exit_signal().await;
+        _ = stop_sender.send(true);
+    });
+
+    let backend: Arc<dyn wg::WgBackend> = Arc::new(wg::Libwg);
+    let reconnects = Arc::new(AtomicU64::new(0));
+    // whether the current session has a working tunnel, for the metrics
+    let tunnel_up = Arc::new(AtomicBool::new(false));
+    let mut reconnect_policy = reconnect::Reconnect::new(reconnect_cooldown);
+    // of the last session, to pick max_reconnect if the next one fails to load
+    let mut vpn_name: Option<String> = None;
+    // failures break out of the loop instead of exiting, so that the killswitch is removed
+    let exit_code = loop {
+        events.publish(event::EventKind::Connecting);
+        let wg_conf = tokio::select! {
+            wg_conf = load_wg_conf(&mut c, wg_quick.as_deref()) => wg_conf,
+            _ = stopped(&mut stop) => break 0,
+        };
+        let mut wg_conf = match (wg_conf, &vpn_name) {
+            (Some(wg_conf), _) => wg_conf,
+            // retrying does not help the first connect or a broken wg-quick file
+            (None, None) => break EPERM,
+            (None, Some(_)) if c.is_none() => break EPERM,
+            (None, Some(vpn_name)) => {
+                let max_reconnect = profile_conf
+                    .profile(vpn_name)
+                    .max_reconnect
+                    .unwrap_or_default();
+                match wait_reconnect(
+                    &mut reconnect_policy,
+                    None,
+                    max_reconnect,
+                    &events,
+                    &reconnects,
+                    &mut stop,
+                )
+                .await
+                {
+                    Some(exit_code) => break exit_code,
+                    None => continue,
                 }
             }
         };
-    }
-    log::info!("start wg-corplink for {}", &name);
-    let wg_conf = wg_conf.unwrap();
-    let protocol = wg_conf.protocol;
-    if !wg::start_wg_go(&name, protocol, with_wg_log) {
-        log::warn!("failed to start wg-corplink for {}", name);
-        exit(EPERM);
-    }
-    let mut uapi = wg::UAPIClient { name: name.clone() };
-    match uapi.config_wg(&wg_conf).await {
-        Ok(_) => {}
-        Err(err) => {
-            log::error!("failed to config interface with uapi for {}: {}", name, err);
-            exit(EPERM);
+        vpn_name = Some(wg_conf.vpn_name.clone());
+        let check_conf = wg::CheckConfig::new(&profile_conf, &wg_conf.vpn_name);
+        let name = wg::unique_interface_name(&interface_name);
+        log::info!("start {} on interface {}", profile_name, name);
+        // before the tunnel is up, so that nothing leaks if it can not be applied
+        if let Some(killswitch) = &mut killswitch {
+            if let Err(e) = killswitch.apply(&name, &wg_conf).await {
+                log::error!("failed to apply killswitch for {}: {}", name, e);
+                break EPERM;
+            }
+        }
+        let protocol = wg_conf.protocol;
+        if !backend.start(&name, protocol, with_wg_log) {
+            log::error!("failed to start wg-corplink for {}", name);
+            break EPERM;
+        }
+        let mut uapi = wg::UAPIClient {
+            name: name.clone(),
+            timeout: uapi_timeout,
+            events: events.clone(),
+            backend: backend.clone(),
+        };
+        match uapi.config_wg(&wg_conf).await {
+            Ok(_) => {}
+            Err(err) => {
+                log::error!("failed to config interface with uapi for {}: {}", name, err);
+                backend.stop();
+                break EPERM;
+            }
+        }
+        // wg-corplink is stopped when the guard goes out of scope, breaking out included
+        let wg_guard = wg::WgGuard::new(backend.clone());
+        let configured_at = Instant::now();
+        // 0 means connected once the config is accepted
+        let mut connected = true;
+        let mut endpoint_wait = handshake_wait;
+        let has_fallbacks = wg_conf
+            .peers
+            .iter()
+            .any(|p| !p.fallback_endpoints.is_empty());
+        if endpoint_wait.is_zero() && has_fallbacks {
+            // the fallbacks are only tried by waiting for the handshake
+            endpoint_wait = Duration::from_secs(wg::DEFAULT_ENDPOINT_WAIT);
+        }
+        if !endpoint_wait.is_zero() {
+            // the connection check below is cancelled at once on a signal
+            let waited = tokio::select! {
+                waited = uapi.wait_for_handshake(&mut wg_conf, endpoint_wait) => Some(waited),
+                _ = stopped(&mut stop) => None,
+            };
+            connected = match waited {
+                Some(Ok(connected)) => connected,
+                Some(Err(err)) => {
+                    log::warn!("failed to wait for handshake of {}: {}", name, err);
+                    false
+                }
+                None => false,
+            };
+        }
+        // set once a handshake is seen, only such a session resets the reconnect backoff
+        let mut connected_at = (connected && !endpoint_wait.is_zero()).then(Instant::now);
+        if connected {
+            tunnel_up.store(true, Ordering::Relaxed);
+            log::info!("{} connected on {}", profile_name, name);
+            events.publish(event::EventKind::Connected {
+                interface: name.clone(),
+            });
+        } else {
+            log::warn!(
+                "no handshake of {} in {}s, the tunnel may not work",
+                name,
+                endpoint_wait.as_secs()
+            );
+        }
+        #[cfg(target_os = "linux")]
+        let mut route_table = None;
+        #[cfg(target_os = "linux")]
+        if let (true, Some(table), Some(fwmark)) =
+            (wg_conf.manage_routes, wg_conf.route_table, wg_conf.fwmark)
+        {
+            match wg::system_routes(&wg_conf) {
+                Ok(routes) => {
+                    let routes = table::RouteTable::apply(&name, table, fwmark, &routes).await;
+                    route_table = Some(routes);
+                }
+                Err(e) => log::warn!("failed to install routes into table {}: {}", table, e),
+            }
+        }
+        if let Some(path) = &address_file {
+            // the first line is the main address
+            let addrs = wg::addresses(&wg_conf);
+            if let Err(e) = std::fs::write(path, addrs.join("\n") + "\n") {
+                log::warn!("failed to write addresses to {}: {}", path, e);
+            }
+        }
+        let mut system_dns = None;
+        if use_vpn_dns {
+            system_dns = Some(dns::SystemDns::apply(&name, &wg_conf.dns).await);
+        }
+        #[cfg(unix)]
+        let status_server = status_socket.as_ref().and_then(|path| {
+            match status::StatusServer::start(path, &uapi, &check_conf) {
+                Ok(server) => Some(server),
+                Err(e) => {
+                    log::warn!("failed to serve status on {}: {}", path, e);
+                    None
+                }
+            }
+        });
+        let mut metrics_server = None;
+        if let Some(addr) = &metrics_listen {
+            let server = metrics::MetricsServer::start(
+                addr,
+                &uapi,
+                &check_conf,
+                tunnel_up.clone(),
+                reconnects.clone(),
+            )
+            .await;
+            match server {
+                Ok(server) => metrics_server = Some(server),
+                Err(e) => log::warn!("failed to serve metrics on {}: {}", addr, e),
+            }
+        }
+
+        // run once the tunnel is fully set up, like PostUp of wg-quick
+        let mut post_up_ok = true;
+        if let Some(cmd) = &post_up {
+            if let Err(err) = hook::run_hook("post_up", cmd, &name, &wg_conf).await {
+                if post_up_fatal {
+                    log::error!("failed to run post_up hook for {}: {}", name, err);
+                    post_up_ok = false;
+                } else {
+                    log::warn!("failed to run post_up hook for {}: {}", name, err);
+                }
+            }
+        }
+
+        // the exit signal cancels the connection check
+        let (cancel, mut cancelled) = oneshot::channel();
+        let mut session_stop = stop.clone();
+        let signal_task = tokio::spawn(async move {
+            stopped(&mut session_stop).await;
+            _ = cancel.send(());
+        });
+
+        let mut exit_code = 0;
+        let mut reconnect = false;
+        tokio::select! {
+            // keep alive
+            _ = async {
+                match &mut c {
+                    Some(c) => c.keep_alive_vpn(&wg_conf, 60).await,
+                    None => future::pending().await,
+                }
+            }, if post_up_ok => {
+                exit_code = ETIMEDOUT;
+            },
+
+            // check wg handshake and reconnect if timeout,
+            // refreshing the endpoints is tried before restarting the device
+            status = async {
+                loop {
+                    let status = uapi.check_wg_connection(&check_conf, &mut cancelled).await;
+                    if let Ok(wg::ConnectionStatus::Timeout) = status {
+                        tunnel_up.store(false, Ordering::Relaxed);
+                        let recovered = tokio::select! {
+                            recovered = uapi.recover(&wg_conf, &check_conf) => recovered,
+                            _ = &mut cancelled => break Ok(wg::ConnectionStatus::Cancelled),
+                        };
+                        match recovered {
+                            Ok(true) => {
+                                tunnel_up.store(true, Ordering::Relaxed);
+                                continue;
+                            }
+                            Ok(false) => {}
+                            Err(err) => {
+                                log::warn!("failed to refresh endpoints of {}: {}", name, err)
+                            }
+                        }
+                    }
+                    break status;
+                }
+            }, if post_up_ok => {
+                match status {
+                    Ok(wg::ConnectionStatus::Timeout) => {
+                        log::warn!("last handshake timeout");
+                        match &on_timeout {
+                            config::TimeoutAction::Reconnect => reconnect = true,
+                            config::TimeoutAction::Exit => exit_code = ETIMEDOUT,
+                            config::TimeoutAction::Hook(cmd) => {
+                                match hook::run_hook("on_timeout", cmd, &name, &wg_conf).await {
+                                    Ok(_) => reconnect = true,
+                                    Err(err) => {
+                                        log::warn!(
+                                            "failed to run on_timeout hook for {}: {}",
+                                            name,
+                                            err
+                                        );
+                                        exit_code = ETIMEDOUT;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    Ok(wg::ConnectionStatus::Stopped) => {
+                        log::warn!("wg-corplink stopped");
+                        exit_code = ETIMEDOUT;
+                    }
+                    Ok(wg::ConnectionStatus::DeviceGone) => {
+                        log::warn!("restart wg-corplink for {}", name);
+                        reconnect = true;
+                    }
+                    Ok(wg::ConnectionStatus::Cancelled) => {}
+                    Err(err) => {
+                        log::error!("failed to check connection of {}: {}", name, err);
+                        reconnect = true;
+                    }
+                }
+            },
+
+            // a fatal post_up failure skips the session, but still shuts down below
+            else => exit_code = EPERM,
+        }
+
+        signal_task.abort();
+
+        // shutdown
+        tunnel_up.store(false, Ordering::Relaxed);
+        if let Some(cmd) = &pre_down {
+            if let Err(err) = hook::run_hook("pre_down", cmd, &name, &wg_conf).await {
+                log::warn!("failed to run pre_down hook for {}: {}", name, err);
+            }
         }
+        if let Some(c) = &mut c {
+            log::info!("disconnecting vpn...");
+            match c.disconnect_vpn(&wg_conf).await {
+                Ok(_) => {}
+                Err(e) => log::warn!("failed to disconnect vpn: {}", e),
+            };
+        }
+
+        if let Some(system_dns) = &mut system_dns {
+            system_dns.restore().await;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(route_table) = &mut route_table {
+            route_table.remove().await;
+        }
+        #[cfg(unix)]
+        if let Some(status_server) = status_server {
+            status_server.stop();
+        }
+        if let Some(metrics_server) = metrics_server {
+            metrics_server.stop();
+        }
+        if let Some(path) = &address_file {
+            if let Err(e) = std::fs::remove_file(path) {
+                log::warn!("failed to remove {}: {}", path, e);
+            }
+        }
+        if connected_at.is_none() && endpoint_wait.is_zero() {
+            // not waited for, so whether it ever handshaked is only known now
+            if let Ok(status) = uapi.get_status().await {
+                if status.peers.iter().any(|p| p.last_handshake.is_some()) {
+                    connected_at = Some(configured_at);
+                }
+            }
+        }
+        if let Err(err) = uapi.bring_down().await {
+            log::warn!("failed to bring down {}: {}", name, err);
+        }
+        drop(wg_guard);
+        events.publish(event::EventKind::Disconnected {
+            interface: name.clone(),
+        });
+
+        if !reconnect {
+            break exit_code;
+        }
+        let max_reconnect = profile_conf
+            .profile(&wg_conf.vpn_name)
+            .max_reconnect
+            .unwrap_or_default();
+        if let Some(exit_code) = wait_reconnect(
+            &mut reconnect_policy,
+            connected_at.map(|t| t.elapsed()),
+            max_reconnect,
+            &events,
+            &reconnects,
+            &mut stop,
+        )
+        .await
+        {
+            break exit_code;
+        }
+    };
+
+    if let Some(killswitch) = &mut killswitch {
+        killswitch.remove().await;
     }
+    log::info!("reach exit");
+    exit(exit_code)
+}
+
+// wait for ctrl+c, or SIGTERM from systemd/kill on unix
+async fn wait_exit_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut stream) => {
+                stream.recv().await;
+            }
+            Err(e) => {
+                log::warn!("failed to receive signal: {}", e);
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
 
-    let mut exit_code = 0;
     tokio::select! {
-        // handle signal
-        _ = async {
-            match tokio::signal::ctrl_c().await {
+        result = signal::ctrl_c() => {
+            match result {
                 Ok(_) => {},
                 Err(e) => {
-                    log::warn!("failed to receive signal: {}",e);
+                    log::warn!("failed to receive signal: {}", e);
                 },
             }
-            log::info!("ctrl+v received");
-        } => {},
-
-        // keep alive
-        _ = c.keep_alive_vpn(&wg_conf, 60) => {
-            exit_code = ETIMEDOUT;
+            log::info!("ctrl+c received");
         },
-
-        // check wg handshake and exit if timeout
-        _ = async {
-            uapi.check_wg_connection().await;
-            log::warn!("last handshake timeout");
-        } => {
-            exit_code = ETIMEDOUT;
+        _ = terminate => {
+            log::info!("sigterm received");
         },
     }
+}
 
-    // shutdown
-    log::info!("disconnecting vpn...");
-    match c.disconnect_vpn(&wg_conf).await {
-        Ok(_) => {}
-        Err(e) => log::warn!("failed to disconnect vpn: {}", e),
+// resolves once an exit signal is received, at once if it has been already
+async fn stopped(stop: &mut watch::Receiver<bool>) {
+    _ = stop.wait_for(|stopped| *stopped).await;
+}
+
+// errors are logged here, none is returned if the conf can not be loaded
+// wait for the backoff before the next session, none to go on or the exit code
+async fn wait_reconnect(
+    policy: &mut reconnect::Reconnect,
+    connected: Option<Duration>,
+    max_reconnect: u32,
+    events: &event::Events,
+    reconnects: &AtomicU64,
+    stop: &mut watch::Receiver<bool>,
+) -> Option<i32> {
+    reconnects.fetch_add(1, Ordering::Relaxed);
+    let backoff = match policy.on_session_end(connected, max_reconnect) {
+        Some(backoff) => backoff,
+        None => {
+            log::error!("reconnect failed {} times in a row, exit", max_reconnect);
+            return Some(ETIMEDOUT);
+        }
     };
+    let attempt = policy.attempt();
+    log::info!(
+        "reconnect attempt {}, waiting {}s",
+        attempt,
+        backoff.as_secs()
+    );
+    events.publish(event::EventKind::Reconnecting { attempt, backoff });
+    tokio::select! {
+        _ = stopped(stop) => return Some(0),
+        _ = tokio::time::sleep(backoff) => {},
+    }
+    log::info!("reconnecting vpn...");
+    None
+}
 
-    wg::stop_wg_go();
+async fn load_wg_conf(c: &mut Option<Client>, wg_quick: Option<&str>) -> Option<WgConf> {
+    match (c, wg_quick) {
+        (Some(c), _) => connect_vpn(c).await,
+        (None, Some(path)) => match WgConf::from_wg_quick(Path::new(path)) {
+            Ok(conf) => {
+                log::info!("load wg conf from {}", path);
+                Some(conf)
+            }
+            Err(e) => {
+                log::error!("failed to load wg conf: {}", e);
+                None
+            }
+        },
+        (None, None) => unreachable!("either corplink client or wg-quick config is required"),
+    }
+}
 
-    log::info!("reach exit");
-    exit(exit_code)
+async fn connect_vpn(c: &mut Client) -> Option<WgConf> {
+    let mut logout_retry = true;
+    loop {
+        if c.need_login() {
+            log::info!("not login yet, try to login");
+            if let Err(e) = c.login().await {
+                log::error!("failed to login: {}", e);
+                return None;
+            }
+            log::info!("login success");
+        }
+        log::info!("try to connect");
+        match c.connect_vpn().await {
+            Ok(conf) => return Some(conf),
+            Err(e) => {
+                if logout_retry && e.to_string().contains("logout") {
+                    // e contains detail message, so just print it out
+                    log::warn!("{}", e);
+                    logout_retry = false;
+                    continue;
+                } else {
+                    log::error!("failed to connect vpn: {}", e);
+                    return None;
+                }
+            }
+        };
+    }
 }
 
 fn check_previlige() {
@@ -189,6 +775,194 @@ fn check_previlige() {
     }
 }
 
+#[cfg(unix)]
+fn is_privileged() -> bool {
+    sudo::check() == sudo::RunningAs::Root
+}
+
+#[cfg(windows)]
+fn is_privileged() -> bool {
+    is_elevated::is_elevated()
+}
+
+// whether a file can be created at path by the current user
+#[cfg(unix)]
+fn dir_writable(path: &str) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(dir) => unsafe { libc::access(dir.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+fn ok_or(ok: bool, msg: &str) -> Result<(), String> {
+    if ok {
+        Ok(())
+    } else {
+        Err(msg.to_string())
+    }
+}
+
+// check what a connection needs without connecting, and print a report of them
+// the 2fa code is read from stdin on every connect without the totp secret,
+// a config failing to load is left to be reported by run
+fn needs_input(conf_file: &str) -> bool {
+    let conf = std::fs::read_to_string(conf_file)
+        .ok()
+        .and_then(|data| serde_json::from_str::<Config>(&data).ok());
+    match conf {
+        Some(conf) => conf.wg_quick.is_none() && conf.code.unwrap_or_default().is_empty(),
+        None => false,
+    }
+}
+
+fn preflight(args: &Args) -> bool {
+    let mut failed = 0;
+    let mut report = |name: &str, result: Result<(), String>| match result {
+        Ok(_) => println!("[ok]   {}", name),
+        Err(e) => {
+            println!("[fail] {}: {}", name, e);
+            failed += 1;
+        }
+    };
+
+    let conf = std::fs::read_to_string(&args.conf_file)
+        .map_err(|e| e.to_string())
+        .and_then(|data| serde_json::from_str::<Config>(&data).map_err(|e| e.to_string()));
+    let conf = match conf {
+        Ok(conf) => {
+            report(&format!("parse {}", args.conf_file), Ok(()));
+            conf
+        }
+        Err(e) => {
+            report(&format!("parse {}", args.conf_file), Err(e));
+            return false;
+        }
+    };
+
+    // keys not set yet are generated on first run
+    for (field, key) in [
+        ("private_key", &conf.private_key),
+        ("public_key", &conf.public_key),
+    ] {
+        if let Some(key) = key {
+            let result = ok_or(
+                utils::is_valid_wg_key(key),
+                "not a valid 32-byte WireGuard key",
+            );
+            report(field, result);
+        }
+    }
+    match &conf.wg_quick {
+        Some(path) => {
+            let result = WgConf::from_wg_quick(Path::new(path))
+                .map_err(|e| e.to_string())
+                .and_then(|wg_conf| wg_conf.validate());
+            report(&format!("wg_quick {}", path), result);
+        }
+        None => {
+            // the wg conf is pushed by server, only the login info can be checked
+            let result = ok_or(
+                conf.server.is_some() || !conf.company_name.is_empty(),
+                "either server or company_name is required",
+            );
+            report("corplink server", result);
+        }
+    }
+
+    let missing = check_dependencies(
+        conf.use_vpn_dns.unwrap_or_default(),
+        conf.killswitch.unwrap_or_default(),
+        conf.route_table.is_some(),
+    );
+    let result = ok_or(missing.is_empty(), &missing.join("; "));
+    report("dependencies", result);
+
+    if args.no_root {
+        println!("[skip] privileges: --no-root is set");
+    } else {
+        let result = ok_or(is_privileged(), "please run as root or administrator");
+        report("privileges", result);
+    }
+
+    #[cfg(unix)]
+    for (name, path) in [
+        ("status_socket", conf.status_socket.as_deref()),
+        ("address_file", conf.address_file.as_deref()),
+        ("--pidfile", args.pidfile.as_deref()),
+        ("--log-file", args.log_file.as_deref()),
+    ] {
+        if let Some(path) = path {
+            let result = ok_or(dir_writable(path), "directory is not writable");
+            report(&format!("{} {}", name, path), result);
+        }
+    }
+
+    if failed > 0 {
+        println!("{} check(s) failed", failed);
+        return false;
+    }
+    println!("all checks passed");
+    true
+}
+
+// collect all the missing external tools at once, instead of failing one by
+// one deep inside the connect flow
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn check_dependencies(
+    use_vpn_dns: bool,
+    use_killswitch: bool,
+    use_route_table: bool,
+) -> Vec<String> {
+    let mut missing = Vec::new();
+
+    #[cfg(target_os = "linux")]
+    if use_vpn_dns && !utils::cmd_exist("resolvectl") && !utils::cmd_exist("resolvconf") {
+        missing.push(
+            "resolvectl or resolvconf is required by use_vpn_dns, \
+            please install systemd-resolved or openresolv"
+                .to_string(),
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    if use_killswitch && !utils::cmd_exist("nft") {
+        missing.push("nft is required by killswitch, please install nftables".to_string());
+    }
+
+    #[cfg(target_os = "linux")]
+    if use_route_table && !utils::cmd_exist("ip") {
+        missing.push("ip is required by route_table, please install iproute2".to_string());
+    }
+
+    #[cfg(windows)]
+    {
+        if use_vpn_dns && !utils::cmd_exist("netsh") {
+            missing.push("netsh is required by use_vpn_dns".to_string());
+        }
+        let beside_exe = env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|dir| dir.join("wintun.dll").is_file()))
+            .unwrap_or_default();
+        if !beside_exe && !utils::cmd_exist("wintun.dll") {
+            missing.push(
+                "wintun.dll is required to start wg-corplink, \
+                please download it from https://www.wintun.net/ \
+                and put it beside corplink-rs"
+                    .to_string(),
+            );
+        }
+    }
+
+    missing
+}
+
 fn print_version() {
     let pkg_name = env!("CARGO_PKG_NAME");
     let pkg_version = env!("CARGO_PKG_VERSION");
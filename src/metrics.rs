@@ -0,0 +1,145 @@
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use crate::wg;
+
+// a client that does not send its request or read the response in time is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// connected is set by the session once the handshake is waited for,
+// and cleared when the handshake times out
+async fn render(
+    uapi: &wg::UAPIClient,
+    check_conf: &wg::CheckConfig,
+    connected: bool,
+    reconnects: u64,
+) -> String {
+    let interface = uapi.name.clone();
+    let mut up = 0;
+    let mut handshake = None;
+    let mut transfer = None;
+    match uapi.get_status().await {
+        Ok(status) => {
+            let last_handshake = check_conf.last_handshake(&status).map(|(_, t)| t);
+            if connected && last_handshake.is_some_and(|t| !check_conf.is_stale(t)) {
+                up = 1;
+            }
+            handshake = last_handshake.map(|t| {
+                t.duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            });
+            transfer = Some(status.transfer());
+        }
+        Err(e) => log::warn!("failed to get status of {}: {}", interface, e),
+    }
+
+    let mut metrics = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        _ = writeln!(metrics, "# HELP {} {}", name, help);
+        _ = writeln!(metrics, "# TYPE {} {}", name, kind);
+        _ = writeln!(metrics, "{}{{interface=\"{}\"}} {}", name, interface, value);
+    };
+    metric("seal_up", "gauge", "whether the tunnel is up", up);
+    metric(
+        "seal_reconnects_total",
+        "counter",
+        "reconnects since started",
+        reconnects,
+    );
+    // left out if unknown, so that a missing value is not mistaken as 0
+    if let Some(handshake) = handshake {
+        metric(
+            "seal_last_handshake_seconds",
+            "gauge",
+            "unix time of the last handshake",
+            handshake,
+        );
+    }
+    if let Some((rx, tx)) = transfer {
+        metric("seal_rx_bytes_total", "counter", "received bytes", rx);
+        metric("seal_tx_bytes_total", "counter", "sent bytes", tx);
+    }
+    metrics
+}
+
+// serves prometheus metrics over http, the path of the request is ignored
+pub struct MetricsServer {
+    task: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    pub async fn start(
+        addr: &str,
+        uapi: &wg::UAPIClient,
+        check_conf: &wg::CheckConfig,
+        connected: Arc<AtomicBool>,
+        reconnects: Arc<AtomicU64>,
+    ) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr).await?;
+        log::info!("serve metrics of {} on {}", uapi.name, addr);
+
+        let uapi = uapi.clone();
+        let check_conf = check_conf.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let mut stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("failed to accept metrics connection: {}", e);
+                        continue;
+                    }
+                };
+                let uapi = uapi.clone();
+                let check_conf = check_conf.clone();
+                let connected = connected.clone();
+                let reconnects = reconnects.clone();
+                // each connection on its own, so a slow client does not block the others
+                tokio::spawn(async move {
+                    // only one request is served per connection, so the rest is not read
+                    let mut buf = [0; 1024];
+                    match tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => {
+                            log::debug!("failed to read metrics request: {}", e);
+                            return;
+                        }
+                        Err(_) => {
+                            log::debug!("metrics request not received in time");
+                            return;
+                        }
+                    }
+                    let up = connected.load(Ordering::Relaxed);
+                    let count = reconnects.load(Ordering::Relaxed);
+                    let body = render(&uapi, &check_conf, up, count).await;
+                    let resp = format!(
+                        "HTTP/1.1 200 OK\r\n\
+                        Content-Type: text/plain; version=0.0.4\r\n\
+                        Content-Length: {}\r\n\
+                        Connection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let write = stream.write_all(resp.as_bytes());
+                    match tokio::time::timeout(REQUEST_TIMEOUT, write).await {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => log::debug!("failed to write metrics: {}", e),
+                        Err(_) => log::debug!("metrics response not sent in time"),
+                    }
+                });
+            }
+        });
+        Ok(MetricsServer { task })
+    }
+
+    pub fn stop(self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,163 @@
+use std::io;
+use std::net::SocketAddr;
+
+// same as wg-quick
+pub const DEFAULT_MTU: u32 = 1420;
+// outer ip header + udp header + wg header
+const WG_OVERHEAD_V4: u32 = 20 + 8 + 32;
+const WG_OVERHEAD_V6: u32 = 40 + 8 + 32;
+// the largest packet we try, ethernet mtu
+const MAX_PATH_MTU: u32 = 1500;
+// ipv6 inside the tunnel needs at least this
+pub const MIN_MTU: u32 = 1280;
+// icmp from a router on the path needs a round trip to arrive,
+// and a probe or its icmp may be lost, so each size is tried a few times
+#[cfg(target_os = "linux")]
+const PROBE_WAIT: std::time::Duration = std::time::Duration::from_millis(300);
+#[cfg(target_os = "linux")]
+const PROBE_ROUNDS: u32 = 3;
+
+#[cfg(target_os = "linux")]
+fn set_sock_opt(fd: i32, level: i32, name: i32, value: i32) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const i32 as *const libc::c_void,
+            std::mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get_sock_opt(fd: i32, level: i32, name: i32) -> io::Result<i32> {
+    let mut value: i32 = 0;
+    let mut len = std::mem::size_of::<i32>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut i32 as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match ret {
+        0 => Ok(value),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+// send udp packets with DF set to the endpoint in decreasing size,
+// the kernel rejects packets larger than the known path mtu with EMSGSIZE
+// and updates it when icmp fragmentation needed messages arrive,
+// the probes are zeros, an invalid message type dropped by wireguard,
+// this blocks for a second or so waiting for icmp
+#[cfg(target_os = "linux")]
+pub fn probe_path_mtu(endpoint: &SocketAddr) -> io::Result<u32> {
+    use std::net::UdpSocket;
+    use std::os::fd::AsRawFd;
+
+    let (bind_addr, level, discover, discover_do, mtu_opt, header) = match endpoint {
+        SocketAddr::V4(_) => (
+            "0.0.0.0:0",
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+            libc::IP_MTU,
+            20 + 8,
+        ),
+        SocketAddr::V6(_) => (
+            "[::]:0",
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+            libc::IPV6_MTU,
+            40 + 8,
+        ),
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.connect(endpoint)?;
+    let fd = socket.as_raw_fd();
+    set_sock_opt(fd, level, discover, discover_do)?;
+
+    let mut mtu = MAX_PATH_MTU;
+    let mut rounds = 0;
+    loop {
+        let probe = vec![0u8; (mtu - header) as usize];
+        match socket.send(&probe) {
+            Ok(_) => {
+                // path mtu may be lowered by icmp after the probe is sent
+                std::thread::sleep(PROBE_WAIT);
+                let known = get_sock_opt(fd, level, mtu_opt)? as u32;
+                if known >= mtu {
+                    rounds += 1;
+                    if rounds >= PROBE_ROUNDS {
+                        return Ok(mtu);
+                    }
+                    continue;
+                }
+                mtu = known;
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => {
+                let known = get_sock_opt(fd, level, mtu_opt)? as u32;
+                if known >= mtu {
+                    return Err(e);
+                }
+                mtu = known;
+            }
+            Err(e) => return Err(e),
+        }
+        rounds = 0;
+        if mtu <= header + WG_OVERHEAD_V6 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("path mtu {mtu} is too small"),
+            ));
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn probe_path_mtu(_endpoint: &SocketAddr) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "path mtu probing is only supported on linux",
+    ))
+}
+
+// detect interface mtu from the path mtu to endpoint,
+// fallback to default mtu if probing fails, blocking like probe_path_mtu
+pub fn detect_mtu(endpoint: &SocketAddr) -> u32 {
+    match probe_path_mtu(endpoint) {
+        Ok(path_mtu) => {
+            let overhead = match endpoint {
+                SocketAddr::V4(_) => WG_OVERHEAD_V4,
+                SocketAddr::V6(_) => WG_OVERHEAD_V6,
+            };
+            match path_mtu.checked_sub(overhead) {
+                Some(mtu) if mtu >= MIN_MTU => {
+                    log::info!(
+                        "path mtu to {endpoint} is {path_mtu}, use mtu {mtu} ({path_mtu} - {overhead})"
+                    );
+                    mtu
+                }
+                _ => {
+                    log::warn!(
+                        "path mtu to {endpoint} is {path_mtu}, too small for the tunnel, use mtu {MIN_MTU}"
+                    );
+                    MIN_MTU
+                }
+            }
+        }
+        Err(e) => {
+            log::warn!("failed to probe path mtu to {endpoint}, use default mtu {DEFAULT_MTU}: {e}");
+            DEFAULT_MTU
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_COOLDOWN: u64 = 5;
+
+const BACKOFF_BASE: u64 = 1;
+const BACKOFF_MAX: u64 = 5 * 60;
+// this many reconnects within the window means the link is flapping
+const FLAP_WINDOW: u64 = 10 * 60;
+const FLAP_THRESHOLD: usize = 5;
+
+// decides how long to wait before the next session, and when to give up
+pub struct Reconnect {
+    cooldown: u64,
+    attempt: u32,
+    recent: VecDeque<Instant>,
+}
+
+impl Reconnect {
+    pub fn new(cooldown: u64) -> Reconnect {
+        Reconnect {
+            cooldown,
+            attempt: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    // connected is how long the session stayed up, max_reconnect is 0 to retry forever
+    // returns the backoff, or none if the reconnects are used up
+    pub fn on_session_end(
+        &mut self,
+        connected: Option<Duration>,
+        max_reconnect: u32,
+    ) -> Option<Duration> {
+        // a session that stayed up for a while does not count as a consecutive failure
+        if connected.is_some_and(|d| d > Duration::from_secs(BACKOFF_MAX)) {
+            self.attempt = 0;
+        }
+        self.attempt += 1;
+
+        let now = Instant::now();
+        self.recent.push_back(now);
+        while self
+            .recent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > Duration::from_secs(FLAP_WINDOW))
+        {
+            self.recent.pop_front();
+        }
+        if self.recent.len() >= FLAP_THRESHOLD {
+            log::warn!(
+                "reconnected {} times in {}s, the link may be flapping",
+                self.recent.len(),
+                FLAP_WINDOW
+            );
+        }
+
+        if max_reconnect != 0 && self.attempt > max_reconnect {
+            return None;
+        }
+        let backoff = BACKOFF_BASE
+            .checked_shl(self.attempt - 1)
+            .unwrap_or(BACKOFF_MAX)
+            .min(BACKOFF_MAX)
+            // the backoff is reset after a long session, the cooldown is not
+            .max(self.cooldown);
+        Some(Duration::from_secs(backoff))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: Option<Duration> = Some(Duration::from_secs(BACKOFF_MAX + 1));
+
+    #[test]
+    fn test_backoff_grows() {
+        let mut r = Reconnect::new(0);
+        let backoffs: Vec<_> = (0..10)
+            .map(|_| r.on_session_end(None, 0).unwrap().as_secs())
+            .collect();
+        assert_eq!(backoffs, [1, 2, 4, 8, 16, 32, 64, 128, 256, 300]);
+        for _ in 0..100 {
+            assert_eq!(r.on_session_end(None, 0), Some(Duration::from_secs(300)));
+        }
+    }
+
+    #[test]
+    fn test_max_reconnect() {
+        let mut r = Reconnect::new(0);
+        assert!(r.on_session_end(None, 2).is_some());
+        assert!(r.on_session_end(Some(Duration::from_secs(1)), 2).is_some());
+        assert_eq!(r.on_session_end(None, 2), None);
+        assert_eq!(r.attempt(), 3);
+    }
+
+    #[test]
+    fn test_reset_after_long_session() {
+        let mut r = Reconnect::new(0);
+        for _ in 0..4 {
+            r.on_session_end(None, 3);
+        }
+        assert_eq!(r.attempt(), 4);
+        assert_eq!(r.on_session_end(LONG, 3), Some(Duration::from_secs(1)));
+        assert_eq!(r.attempt(), 1);
+        // a short session is still a failure
+        assert_eq!(
+            r.on_session_end(Some(Duration::from_secs(10)), 3),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let mut r = Reconnect::new(5);
+        assert_eq!(r.on_session_end(LONG, 0), Some(Duration::from_secs(5)));
+        assert_eq!(r.on_session_end(None, 0), Some(Duration::from_secs(5)));
+        assert_eq!(r.on_session_end(None, 0), Some(Duration::from_secs(5)));
+        assert_eq!(r.on_session_end(None, 0), Some(Duration::from_secs(8)));
+        assert_eq!(r.on_session_end(LONG, 0), Some(Duration::from_secs(5)));
+    }
+}
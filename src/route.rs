@@ -0,0 +1,170 @@
+use std::io;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+// a bare address is treated as a host route according to its family,
+// so that 10.0.0.1 becomes 10.0.0.1/32 and fd00::1 becomes fd00::1/128
+pub fn parse_route(route: &str) -> io::Result<IpNet> {
+    let route = route.trim();
+    let result = if route.contains('/') {
+        route.parse::<IpNet>().map_err(|e| e.to_string())
+    } else {
+        route
+            .parse::<IpAddr>()
+            .map(IpNet::from)
+            .map_err(|e| e.to_string())
+    };
+    result.map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid route {route}: {e}"),
+        )
+    })
+}
+
+// an entry may hold several routes separated by commas or whitespace,
+// e.g. "10.0.0.0/8, 172.16.0.0/12," from some servers
+pub fn parse_routes(routes: &[String]) -> io::Result<Vec<IpNet>> {
+    routes
+        .iter()
+        .flat_map(|entry| entry.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|route| !route.is_empty())
+        .map(parse_route)
+        .collect()
+}
+
+// drop routes that are duplicated or contained in another route,
+// the order of the remaining ones is kept
+pub fn collapse_routes(routes: &[IpNet]) -> Vec<IpNet> {
+    let routes: Vec<IpNet> = routes.iter().map(|route| route.trunc()).collect();
+    routes
+        .iter()
+        .enumerate()
+        .filter(|(i, route)| {
+            !routes.iter().enumerate().any(|(j, other)| {
+                // of the equal ones keep the first
+                (other != *route && other.contains(*route)) || (other == *route && j < *i)
+            })
+        })
+        .map(|(_, route)| *route)
+        .collect()
+}
+
+// remove the excluded ranges from routes, a route partially covering
+// an excluded range is split into the sub-prefixes around it
+pub fn exclude_routes(routes: &[IpNet], excludes: &[IpNet]) -> Vec<IpNet> {
+    let mut result = Vec::new();
+    for route in routes {
+        exclude_route(*route, excludes, &mut result);
+    }
+    result
+}
+
+fn exclude_route(route: IpNet, excludes: &[IpNet], result: &mut Vec<IpNet>) {
+    if excludes.iter().any(|exclude| exclude.contains(&route)) {
+        // fully excluded
+        return;
+    }
+    if !excludes.iter().any(|exclude| route.contains(exclude)) {
+        // no overlap
+        result.push(route);
+        return;
+    }
+    // split into two halves and check them separately, this always
+    // succeeds because a max length prefix can not contain anything else
+    for half in route.subnets(route.prefix_len() + 1).unwrap() {
+        exclude_route(half, excludes, result);
+    }
+}
+
+// replace a default route with its two halves, so that they take precedence
+// over the default route of the system without removing it, the endpoints must
+// be excluded from the result, or the tunnel packets are routed into the tunnel
+pub fn split_default_routes(routes: &[IpNet]) -> Vec<IpNet> {
+    let mut result = Vec::new();
+    for route in routes {
+        if route.prefix_len() == 0 {
+            // a /0 always has two /1 subnets
+            result.extend(route.subnets(1).unwrap());
+        } else {
+            result.push(*route);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(route: &str) -> IpNet {
+        route.parse().unwrap()
+    }
+
+    fn nets(routes: &[&str]) -> Vec<IpNet> {
+        routes.iter().map(|route| net(route)).collect()
+    }
+
+    #[test]
+    fn test_parse_route() {
+        assert_eq!(parse_route("10.0.0.0/8").unwrap(), net("10.0.0.0/8"));
+        assert_eq!(parse_route(" 10.0.0.1 ").unwrap(), net("10.0.0.1/32"));
+        assert_eq!(parse_route("fd00::1").unwrap(), net("fd00::1/128"));
+        assert!(parse_route("10.0.0.0/33").is_err());
+        assert!(parse_route("example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_routes() {
+        let routes = [
+            "10.0.0.0/8, 172.16.0.0/12,".to_string(),
+            "192.168.0.0/16\t10.1.1.1 ,,".to_string(),
+            "".to_string(),
+            "fd00::/8".to_string(),
+        ];
+        assert_eq!(
+            parse_routes(&routes).unwrap(),
+            nets(&[
+                "10.0.0.0/8",
+                "172.16.0.0/12",
+                "192.168.0.0/16",
+                "10.1.1.1/32",
+                "fd00::/8"
+            ])
+        );
+        assert!(parse_routes(&["10.0.0.0/8,bogus".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_collapse_routes() {
+        let routes = nets(&["10.1.0.0/16", "10.0.0.0/8", "10.1.2.3/8", "192.168.1.1/32"]);
+        assert_eq!(
+            collapse_routes(&routes),
+            nets(&["10.0.0.0/8", "192.168.1.1/32"])
+        );
+    }
+
+    #[test]
+    fn test_exclude_routes() {
+        let routes = nets(&["10.0.0.0/8", "192.168.0.0/16"]);
+        let excludes = nets(&["10.128.0.0/9", "192.168.0.0/16"]);
+        assert_eq!(exclude_routes(&routes, &excludes), nets(&["10.0.0.0/9"]));
+
+        let routes = nets(&["10.0.0.0/30"]);
+        let excludes = nets(&["10.0.0.1/32"]);
+        assert_eq!(
+            exclude_routes(&routes, &excludes),
+            nets(&["10.0.0.0/32", "10.0.0.2/31"])
+        );
+    }
+
+    #[test]
+    fn test_split_default_routes() {
+        let routes = nets(&["0.0.0.0/0", "10.0.0.0/8", "::/0"]);
+        assert_eq!(
+            split_default_routes(&routes),
+            nets(&["0.0.0.0/1", "128.0.0.0/1", "10.0.0.0/8", "::/1", "8000::/1"])
+        );
+    }
+}
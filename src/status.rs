@@ -0,0 +1,191 @@
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixListener;
+use tokio::task::JoinHandle;
+
+use crate::wg;
+
+// the schema is part of the interface, only add fields to it
+#[derive(Serialize)]
+struct Timestamp {
+    epoch: u64,
+    rfc3339: String,
+}
+
+#[derive(Serialize)]
+struct PeerReport {
+    public_key: String,
+    // first 8 hex chars of the sha256 of the key, as shown in logs
+    fingerprint: String,
+    endpoint: Option<String>,
+    last_handshake: Option<Timestamp>,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    // effective persistent keepalive in seconds, 0 means disabled
+    keepalive: u32,
+}
+
+#[derive(Serialize)]
+struct StatusReport {
+    interface: String,
+    listen_port: Option<u16>,
+    // last handshake chosen by handshake_policy
+    last_handshake: Option<Timestamp>,
+    stale: bool,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    // in seconds, how often the handshake is checked and how old it can be
+    poll_interval: u64,
+    stale_timeout: u64,
+    peers: Vec<PeerReport>,
+}
+
+fn to_timestamp(t: SystemTime) -> Timestamp {
+    let epoch = t
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let rfc3339 = chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339();
+    Timestamp { epoch, rfc3339 }
+}
+
+// without any handshake, it is stale once the stale timeout has passed since
+// the server started, like check_wg_connection does
+fn is_stale(
+    check_conf: &wg::CheckConfig,
+    handshake: Option<SystemTime>,
+    since: SystemTime,
+) -> bool {
+    check_conf.is_stale(handshake.unwrap_or(since))
+}
+
+async fn report(
+    uapi: &wg::UAPIClient,
+    check_conf: &wg::CheckConfig,
+    since: SystemTime,
+) -> Result<String, wg::Error> {
+    let status = uapi.get_status().await?;
+    let (rx_bytes, tx_bytes) = status.transfer();
+    let handshake = check_conf.last_handshake(&status).map(|(_, t)| t);
+    let report = StatusReport {
+        interface: uapi.name.clone(),
+        listen_port: status.listen_port,
+        last_handshake: handshake.map(to_timestamp),
+        stale: is_stale(check_conf, handshake, since),
+        rx_bytes,
+        tx_bytes,
+        poll_interval: check_conf.poll_interval.as_secs(),
+        stale_timeout: check_conf.stale_timeout.as_secs(),
+        peers: status
+            .peers
+            .iter()
+            .map(|peer| PeerReport {
+                public_key: peer.public_key.clone(),
+                fingerprint: peer.fingerprint.clone(),
+                endpoint: peer.endpoint.map(|addr| addr.to_string()),
+                last_handshake: peer.last_handshake.map(to_timestamp),
+                rx_bytes: peer.rx_bytes,
+                tx_bytes: peer.tx_bytes,
+                keepalive: peer.keepalive,
+            })
+            .collect(),
+    };
+    Ok(serde_json::to_string(&report).unwrap())
+}
+
+// serves one line of json to every client connecting to the unix socket
+pub struct StatusServer {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl StatusServer {
+    pub fn start(
+        path: &str,
+        uapi: &wg::UAPIClient,
+        check_conf: &wg::CheckConfig,
+    ) -> io::Result<StatusServer> {
+        let path = PathBuf::from(path);
+        // remove the socket left by a previous session, but never a file
+        // that is not a socket, e.g. when the path is a typo
+        match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(&path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let listener = UnixListener::bind(&path)?;
+        log::info!("serve status of {} on {}", uapi.name, path.display());
+
+        let uapi = uapi.clone();
+        let check_conf = check_conf.clone();
+        let since = SystemTime::now();
+        let task = tokio::spawn(async move {
+            loop {
+                let mut stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("failed to accept status connection: {}", e);
+                        continue;
+                    }
+                };
+                let mut resp = match report(&uapi, &check_conf, since).await {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        log::warn!("failed to get status of {}: {}", uapi.name, e);
+                        serde_json::json!({ "error": e.to_string() }).to_string()
+                    }
+                };
+                resp.push('\n');
+                if let Err(e) = stream.write_all(resp.as_bytes()).await {
+                    log::debug!("failed to write status: {}", e);
+                }
+            }
+        });
+        Ok(StatusServer { path, task })
+    }
+
+    pub fn stop(self) {
+        self.task.abort();
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::config;
+
+    #[test]
+    fn test_stale() {
+        let check_conf = wg::CheckConfig {
+            poll_interval: Duration::from_secs(1),
+            stale_timeout: Duration::from_secs(60),
+            handshake_policy: config::HandshakePolicy::Any,
+            stale_detection: config::StaleDetection::Time,
+            degrade_ratio: 0.6,
+            quiet: false,
+        };
+        let now = SystemTime::now();
+        let ago = |sec| now - Duration::from_secs(sec);
+        assert!(!is_stale(&check_conf, Some(ago(10)), ago(100)));
+        assert!(is_stale(&check_conf, Some(ago(100)), ago(100)));
+        // no handshake yet is only given the stale timeout to happen
+        assert!(!is_stale(&check_conf, None, ago(10)));
+        assert!(is_stale(&check_conf, None, ago(100)));
+    }
+}
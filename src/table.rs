@@ -0,0 +1,101 @@
+use std::io;
+
+use ipnet::IpNet;
+
+use crate::utils::run_cmd;
+
+// an ip rule without the action, so that the same one can be added and deleted
+struct Rule {
+    family: &'static str,
+    selector: Vec<String>,
+}
+
+impl Rule {
+    fn new(family: &'static str, selector: &[&str]) -> Rule {
+        let selector = selector.iter().map(|s| s.to_string()).collect();
+        Rule { family, selector }
+    }
+
+    async fn run(&self, action: &str) -> io::Result<()> {
+        let mut args = vec![self.family, "rule", action];
+        args.extend(self.selector.iter().map(|s| s.as_str()));
+        run_cmd("ip", &args, None).await
+    }
+}
+
+// routes installed into a separate routing table and selected by ip rules,
+// like Table= of wg-quick, so that the main table is left untouched
+pub struct RouteTable {
+    table: String,
+    rules: Vec<Rule>,
+}
+
+impl RouteTable {
+    // the packets of the tunnel itself carry the fwmark, and skip the table
+    pub async fn apply(interface: &str, table: u32, fwmark: u32, routes: &[IpNet]) -> RouteTable {
+        let table = table.to_string();
+        for route in routes {
+            let route = route.to_string();
+            let args = [
+                "route", "replace", &route, "dev", interface, "table", &table,
+            ];
+            if let Err(e) = run_cmd("ip", &args, None).await {
+                log::warn!("failed to add route {} to table {}: {}", route, table, e);
+            }
+        }
+
+        let fwmark = fwmark.to_string();
+        let mut rules = Vec::new();
+        for (family, v4) in [("-4", true), ("-6", false)] {
+            let family_routes: Vec<&IpNet> = routes
+                .iter()
+                .filter(|route| matches!(route, IpNet::V4(_)) == v4)
+                .collect();
+            if family_routes.is_empty() {
+                continue;
+            }
+            rules.push(Rule::new(
+                family,
+                &["not", "fwmark", &fwmark, "table", &table],
+            ));
+            // the more specific routes of the main table, like the lan, still win over
+            // a default route in the table
+            if family_routes.iter().any(|route| route.prefix_len() == 0) {
+                rules.push(Rule::new(
+                    family,
+                    &["table", "main", "suppress_prefixlength", "0"],
+                ));
+            }
+        }
+        for rule in &rules {
+            // a rule left by a crashed session is replaced instead of duplicated
+            _ = rule.run("del").await;
+            if let Err(e) = rule.run("add").await {
+                log::warn!("failed to add ip rule for table {}: {}", table, e);
+            }
+        }
+        log::info!(
+            "{} route(s) of {} are in table {}",
+            routes.len(),
+            interface,
+            table
+        );
+        RouteTable { table, rules }
+    }
+
+    pub async fn remove(&mut self) {
+        for rule in self.rules.drain(..) {
+            if let Err(e) = rule.run("del").await {
+                log::warn!("failed to delete ip rule for table {}: {}", self.table, e);
+            }
+        }
+        // the routes are gone with the interface anyway, flush what is left in the table
+        for family in ["-4", "-6"] {
+            let args = [family, "route", "flush", "table", &self.table];
+            if let Err(e) = run_cmd("ip", &args, None).await {
+                log::debug!("failed to flush table {}: {}", self.table, e);
+            }
+        }
+        log::info!("table {} cleaned up", self.table);
+    }
+}
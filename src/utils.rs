@@ -1,16 +1,61 @@
+use std::env;
 use std::error::Error;
 use std::io::{self, BufRead};
+use std::process::Stdio;
 
 use base32::Alphabet;
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD as base64;
 use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
 use x25519_dalek::{PublicKey, StaticSecret};
 
 pub async fn read_line() -> String {
     io::stdin().lock().lines().next().unwrap().unwrap()
 }
 
+// run an external tool, feeding input to its stdin if any,
+// and turn a non-zero exit into an error carrying its stderr
+pub async fn run_cmd(cmd: &str, args: &[&str], input: Option<&str>) -> io::Result<()> {
+    log::debug!("run {} {}", cmd, args.join(" "));
+    let mut child = Command::new(cmd)
+        .args(args)
+        .stdin(match input {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(input) = input {
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(input.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} exits with {}: {}",
+            cmd,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+// check if cmd can be found in PATH
+pub fn cmd_exist(cmd: &str) -> bool {
+    let paths = match env::var_os("PATH") {
+        Some(paths) => paths,
+        None => return false,
+    };
+    env::split_paths(&paths).any(|dir| {
+        dir.join(cmd).is_file() || (cfg!(windows) && dir.join(format!("{cmd}.exe")).is_file())
+    })
+}
+
 pub fn b32_decode(s: &str) -> Vec<u8> {
     base32::decode(Alphabet::RFC4648 { padding: true }, s).unwrap()
 }
@@ -34,11 +79,82 @@ pub fn gen_public_key_from_private(private_key: &String) -> Result<String, Box<d
     }
 }
 
-pub fn b64_decode_to_hex(s: &str) -> String {
-    let data = base64.decode(s).unwrap();
+pub fn is_valid_wg_key(s: &str) -> bool {
+    matches!(base64.decode(s), Ok(key) if key.len() == 32)
+}
+
+// short and stable id of a wg key for logs, the first 8 hex chars of the sha256
+// of the raw key, the same whether the key is in base64 or hex,
+// and "invalid" if it is neither
+pub fn key_fingerprint(key: &str) -> String {
+    let raw = match base64.decode(key) {
+        Ok(raw) if raw.len() == 32 => raw,
+        // from_str_radix alone would take a sign like +f
+        _ if key.len() == 64 && key.bytes().all(|b| b.is_ascii_hexdigit()) => (0..key.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&key[i..i + 2], 16).unwrap())
+            .collect(),
+        _ => return "invalid".to_string(),
+    };
+    Sha256::digest(raw)[..4]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+pub fn b64_decode_to_hex(s: &str) -> Result<String, base64::DecodeError> {
+    let data = base64.decode(s)?;
     let mut hex = String::new();
     for c in data {
         hex.push_str(format!("{c:02x}").as_str());
     }
-    hex
+    Ok(hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bytes 0 to 31
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    #[test]
+    fn test_b64_decode_to_hex() {
+        let hex: String = (0..32u8).map(|b| format!("{b:02x}")).collect();
+        assert_eq!(b64_decode_to_hex(KEY).unwrap(), hex);
+        assert!(is_valid_wg_key(KEY));
+    }
+
+    #[test]
+    fn test_b64_decode_to_hex_wrong_length() {
+        // 16 bytes decode fine, but are not a wg key
+        let short = "AAECAwQFBgcICQoLDA0ODw==";
+        assert_eq!(
+            b64_decode_to_hex(short).unwrap(),
+            "000102030405060708090a0b0c0d0e0f"
+        );
+        assert!(!is_valid_wg_key(short));
+        assert!(!is_valid_wg_key(""));
+    }
+
+    #[test]
+    fn test_b64_decode_to_hex_invalid() {
+        assert!(b64_decode_to_hex("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8!").is_err());
+        assert!(b64_decode_to_hex("not base64").is_err());
+        assert!(!is_valid_wg_key("not base64"));
+    }
+
+    #[test]
+    fn test_key_fingerprint() {
+        let hex: String = (0..32u8).map(|b| format!("{b:02x}")).collect();
+        let fingerprint = key_fingerprint(KEY);
+        assert_eq!(fingerprint.len(), 8);
+        assert_eq!(key_fingerprint(&hex), fingerprint);
+        assert_eq!(key_fingerprint(&hex.to_uppercase()), fingerprint);
+        assert_eq!(key_fingerprint("AAECAwQFBgcICQoLDA0ODw=="), "invalid");
+        assert_eq!(key_fingerprint(&hex[..62]), "invalid");
+        assert_eq!(key_fingerprint(&format!("{}zz", &hex[..62])), "invalid");
+        assert_eq!(key_fingerprint(&format!("{}+f", &hex[..62])), "invalid");
+        assert_eq!(key_fingerprint(""), "invalid");
+    }
 }
@@ -1,8 +1,15 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::io;
-use std::time;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{self, SystemTime};
 use std::ffi::{c_char, c_void, CStr, CString};
 
-use crate::{config, utils};
+use tokio::sync::oneshot;
+
+use crate::event::{EventKind, Events};
+use crate::{config, mtu, route, utils};
 
 #[allow(clippy::all)]
 #[allow(dead_code, non_camel_case_types, non_snake_case, non_upper_case_globals)]
@@ -10,6 +17,21 @@ mod libwg {
     include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 }
 
+// IFNAMSIZ - 1 on linux
+const MAX_INTERFACE_NAME_LEN: usize = 15;
+const DEFAULT_KEEPALIVE: u32 = 10;
+// default refresh key timeout of wg is 2 min
+// we set wg connection timeout to 5 min
+const DEFAULT_CHECK_INTERVAL: u64 = 5 * 60;
+const DEFAULT_CHECK_TIMEOUT: u64 = 5 * 60;
+// warn before the stale timeout is reached
+const DEFAULT_DEGRADE_RATIO: f64 = 0.6;
+pub const DEFAULT_UAPI_TIMEOUT: u64 = 10;
+// how long a refreshed endpoint has to bring back the handshake
+const RECOVER_WINDOW: u64 = 30;
+// how long each endpoint of a peer is waited for, if handshake_wait is not set
+pub const DEFAULT_ENDPOINT_WAIT: u64 = 5;
+
 fn start_wg(log_level: i32, interface_name: &str) -> i32 {
     let name = interface_name.as_bytes();
     unsafe { libwg::startWg(log_level, to_c_char_array(name)) }
@@ -33,130 +55,1341 @@ fn uapi(buff: &[u8]) -> Vec<u8> {
 }
 
 
-pub fn stop_wg_go() {
-    stop_wg();
+#[derive(Debug)]
+pub enum Error {
+    // name of the config field holding the key
+    InvalidKey(String),
+    InvalidConfig(String),
+    // uapi response does not contain errno
+    UapiRejected(String),
+    // uapi returns errno=N with N != 0, with the whole response
+    UapiErrno(i64, String),
+    InvalidResponse(String),
+    Io(io::Error),
 }
 
-pub fn start_wg_go(
-    name: &str,
-    protocol: i32,
-    with_log: bool,
-) -> bool {
-    // TODO: support tcp tun
-    _ = protocol;
-    log::info!("start wg-corplink");
-    let mut log_level = libwg::LogLevelError;
-    if with_log {
-        log_level = libwg::LogLevelVerbose;
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidKey(field) => {
+                write!(f, "{} is not a valid 32-byte WireGuard key", field)
+            }
+            Error::InvalidConfig(msg) => write!(f, "{}", msg),
+            Error::UapiRejected(resp) => write!(f, "uapi returns unexpected result: {}", resp),
+            Error::UapiErrno(errno, resp) => {
+                write!(f, "uapi returns errno={}, response: {}", errno, resp)
+            }
+            Error::InvalidResponse(msg) => write!(f, "uapi returns invalid response: {}", msg),
+            Error::Io(err) => err.fmt(f),
+        }
     }
-    let ret = start_wg(log_level, name);
-    matches!(ret, 0)
 }
 
-pub struct UAPIClient {
-    pub name: String,
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
 }
 
-impl UAPIClient {
-    pub async fn config_wg(&mut self, conf: &config::WgConf) -> io::Result<()> {
-        let mut buff = String::from("set=1\n");
-        // standard wg-go uapi operations
-        // see https://www.wireguard.com/xplatform/#configuration-protocol
-        let private_key = utils::b64_decode_to_hex(&conf.private_key);
-        let public_key = utils::b64_decode_to_hex(&conf.peer_key);
-        buff.push_str(format!("private_key={private_key}\n").as_str());
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::InvalidKey(_) | Error::InvalidConfig(_) => {
+                io::Error::new(io::ErrorKind::InvalidInput, err)
+            }
+            Error::UapiRejected(_) | Error::UapiErrno(..) => io::Error::other(err),
+            Error::InvalidResponse(_) => io::Error::new(io::ErrorKind::InvalidData, err),
+            Error::Io(err) => err,
+        }
+    }
+}
+
+fn key_to_hex(field: &str, key: &str) -> Result<String, Error> {
+    if !utils::is_valid_wg_key(key) {
+        return Err(Error::InvalidKey(field.to_string()));
+    }
+    utils::b64_decode_to_hex(key).map_err(|_| Error::InvalidKey(field.to_string()))
+}
+
+#[cfg(unix)]
+fn interface_exists(name: &str) -> bool {
+    match CString::new(name) {
+        Ok(name) => unsafe { libc::if_nametoindex(name.as_ptr()) != 0 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(windows)]
+fn interface_exists(_name: &str) -> bool {
+    false
+}
+
+// find an interface name not taken by another session, e.g. corplink,
+// corplink1, corplink2, so that tunnels of different configs can run side by
+// side, numeric suffix keeps the utun[0-9]* format required on macos
+pub fn unique_interface_name(name: &str) -> String {
+    if !interface_exists(name) {
+        return name.to_string();
+    }
+    for i in 1..100 {
+        let suffix = i.to_string();
+        let base_len = name
+            .chars()
+            .count()
+            .min(MAX_INTERFACE_NAME_LEN - suffix.len());
+        let candidate = format!(
+            "{}{}",
+            name.chars().take(base_len).collect::<String>(),
+            suffix
+        );
+        if !interface_exists(&candidate) {
+            log::info!(
+                "interface {} already exists, use {} instead",
+                name,
+                candidate
+            );
+            return candidate;
+        }
+    }
+    log::warn!("failed to find an unused interface name for {}", name);
+    name.to_string()
+}
+
+// the device behind UAPIClient, so that the connect flow can run without
+// a real wg-corplink
+pub trait WgBackend: Send + Sync {
+    fn start(&self, name: &str, protocol: config::Protocol, with_log: bool) -> bool;
+    // one uapi exchange, both set=1 and get=1 go through it
+    fn uapi(&self, buff: &[u8]) -> Vec<u8>;
+    fn stop(&self);
+}
+
+// wg-corplink linked through libwg
+pub struct Libwg;
+
+// libwg only exposes the udp bind, tcp tun needs support from wg-corplink
+fn check_protocol(protocol: config::Protocol) -> Result<(), String> {
+    match protocol {
+        config::Protocol::Udp => Ok(()),
+        config::Protocol::Tcp => {
+            Err("tcp tun is not supported by the linked wg-corplink".to_string())
+        }
+    }
+}
+
+impl WgBackend for Libwg {
+    fn start(&self, name: &str, protocol: config::Protocol, with_log: bool) -> bool {
+        if let Err(e) = check_protocol(protocol) {
+            log::error!("{}", e);
+            return false;
+        }
+        let (log_level, level_name) = if with_log {
+            (libwg::LogLevelVerbose, "verbose")
+        } else {
+            (libwg::LogLevelError, "error")
+        };
+        log::info!(
+            "start wg-corplink: interface={} protocol={}({}) log_level={}",
+            name,
+            protocol,
+            protocol.mode(),
+            level_name
+        );
+        let ret = start_wg(log_level, name);
+        matches!(ret, 0)
+    }
+
+    fn uapi(&self, buff: &[u8]) -> Vec<u8> {
+        uapi(buff)
+    }
+
+    fn stop(&self) {
+        stop_wg();
+    }
+}
+
+// stops the device when dropped, so that a panic or an early return does not
+// leave the interface with its addresses and routes behind, the routes are
+// removed by the system together with the interface
+pub struct WgGuard {
+    backend: Arc<dyn WgBackend>,
+}
+
+impl WgGuard {
+    pub fn new(backend: Arc<dyn WgBackend>) -> WgGuard {
+        WgGuard { backend }
+    }
+}
+
+impl Drop for WgGuard {
+    fn drop(&mut self) {
+        self.backend.stop();
+    }
+}
+
+// records the operations and answers get=1 with a canned status
+#[cfg(test)]
+pub struct MockBackend {
+    pub calls: Mutex<Vec<String>>,
+    pub status: Mutex<String>,
+}
+
+#[cfg(test)]
+impl MockBackend {
+    pub fn new(status: &str) -> MockBackend {
+        MockBackend {
+            calls: Mutex::new(Vec::new()),
+            status: Mutex::new(status.to_string()),
+        }
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl WgBackend for MockBackend {
+    fn start(&self, name: &str, protocol: config::Protocol, _with_log: bool) -> bool {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(format!("start {} {}", name, protocol));
+        true
+    }
+
+    fn uapi(&self, buff: &[u8]) -> Vec<u8> {
+        let request = String::from_utf8_lossy(buff).to_string();
+        let get = request.starts_with("get=1");
+        self.calls.lock().unwrap().push(request);
+        if get {
+            self.status.lock().unwrap().clone().into_bytes()
+        } else {
+            b"errno=0\n\n".to_vec()
+        }
+    }
+
+    fn stop(&self) {
+        self.calls.lock().unwrap().push("stop".to_string());
+    }
+}
+
+#[derive(Clone)]
+pub struct CheckConfig {
+    // how often to query the last handshake
+    pub poll_interval: time::Duration,
+    // how old the last handshake can be before the connection is considered dead
+    pub stale_timeout: time::Duration,
+    // whether any or all of the peers need a recent handshake
+    pub handshake_policy: config::HandshakePolicy,
+    // judge the connection by handshake time, transferred bytes or both
+    pub stale_detection: config::StaleDetection,
+    // fraction of the stale timeout to warn that the handshake is degrading
+    pub degrade_ratio: f64,
+    // demote the lines logged on every check of a healthy connection to trace
+    pub quiet: bool,
+}
+
+impl CheckConfig {
+    pub fn new(conf: &config::Config, vpn_name: &str) -> CheckConfig {
+        let profile = conf.profile(vpn_name);
+        CheckConfig {
+            poll_interval: time::Duration::from_secs(
+                profile.check_interval.unwrap_or(DEFAULT_CHECK_INTERVAL),
+            ),
+            stale_timeout: time::Duration::from_secs(
+                profile.check_timeout.unwrap_or(DEFAULT_CHECK_TIMEOUT),
+            ),
+            handshake_policy: conf.handshake_policy.unwrap_or_default(),
+            stale_detection: conf.stale_detection.unwrap_or_default(),
+            degrade_ratio: conf
+                .degrade_ratio
+                .filter(|ratio| *ratio > 0.0 && *ratio < 1.0)
+                .unwrap_or(DEFAULT_DEGRADE_RATIO),
+            quiet: conf.quiet_check.unwrap_or_default(),
+        }
+    }
+
+    fn healthy_level(&self, level: log::Level) -> log::Level {
+        if self.quiet {
+            log::Level::Trace
+        } else {
+            level
+        }
+    }
+
+    // with any policy the newest handshake decides, with all policy the oldest one,
+    // and there is none as long as a peer has no handshake yet
+    pub fn last_handshake<'a>(&self, status: &'a WgStatus) -> Option<(&'a PeerStatus, SystemTime)> {
+        let handshakes = status
+            .peers
+            .iter()
+            .map(|peer| peer.last_handshake.map(|t| (peer, t)));
+        match self.handshake_policy {
+            config::HandshakePolicy::Any => handshakes.flatten().max_by_key(|(_, t)| *t),
+            config::HandshakePolicy::All => handshakes
+                .collect::<Option<Vec<_>>>()?
+                .into_iter()
+                .min_by_key(|(_, t)| *t),
+        }
+    }
+
+    pub fn is_stale(&self, last_handshake: SystemTime) -> bool {
+        match SystemTime::now().duration_since(last_handshake) {
+            Ok(elapsed) => elapsed > self.stale_timeout,
+            // handshake in the future, the clock has been adjusted
+            Err(_) => false,
+        }
+    }
+}
+
+pub enum ConnectionStatus {
+    // last handshake is older than the stale timeout
+    Timeout,
+    // device has no peer any more
+    Stopped,
+    // the interface is removed under wg-corplink, the device needs a restart
+    DeviceGone,
+    // the caller asks to stop checking, e.g. on disconnect by user
+    Cancelled,
+}
+
+#[derive(Default)]
+pub struct PeerStatus {
+    // hex encoded, as returned by uapi
+    pub public_key: String,
+    // see utils::key_fingerprint
+    pub fingerprint: String,
+    pub endpoint: Option<SocketAddr>,
+    // none if no handshake has happened yet
+    pub last_handshake: Option<SystemTime>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    // persistent keepalive interval in seconds, 0 means disabled
+    pub keepalive: u32,
+}
+
+#[derive(Default)]
+pub struct WgStatus {
+    // the port picked by wg-corplink if it is not set
+    pub listen_port: Option<u16>,
+    pub peers: Vec<PeerStatus>,
+}
+
+impl WgStatus {
+    // total (rx_bytes, tx_bytes) of all peers
+    pub fn transfer(&self) -> (u64, u64) {
+        self.peers.iter().fold((0, 0), |(rx, tx), peer| {
+            (rx + peer.rx_bytes, tx + peer.tx_bytes)
+        })
+    }
+}
+
+// check the errno line of a uapi response, which ends with a blank line,
+// diagnostic lines may come before errno
+fn parse_errno(resp: &str) -> Result<(), Error> {
+    let lines: Vec<&str> = resp
+        .split('\n')
+        .take_while(|line| !line.is_empty())
+        .collect();
+    let errno = match lines.iter().find_map(|line| line.strip_prefix("errno=")) {
+        Some(errno) => errno,
+        None => return Err(Error::UapiRejected(resp.to_string())),
+    };
+    match errno.trim().parse::<i64>() {
+        Ok(0) => Ok(()),
+        Ok(errno) => Err(Error::UapiErrno(errno, lines.join(", "))),
+        Err(_) => Err(Error::InvalidResponse(format!("errno={}", errno))),
+    }
+}
+
+// parse the response of get=1, see
+// https://www.wireguard.com/xplatform/#configuration-protocol
+fn parse_status(resp: &str) -> Result<WgStatus, Error> {
+    let invalid = |line: &str| Error::InvalidResponse(line.to_string());
+    let mut status = WgStatus::default();
+    let mut complete = false;
+    for line in resp.split('\n') {
+        if line.is_empty() {
+            // reach end
+            break;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some(kv) => kv,
+            None => return Err(invalid(line)),
+        };
+        match key {
+            "errno" => {
+                parse_errno(line)?;
+                complete = true;
+            }
+            // public_key starts a new peer section
+            "public_key" => status.peers.push(PeerStatus {
+                public_key: value.to_string(),
+                fingerprint: utils::key_fingerprint(value),
+                ..Default::default()
+            }),
+            // device keys come before all the peers
+            "listen_port" if status.peers.is_empty() => {
+                status.listen_port = Some(value.parse().map_err(|_| invalid(line))?)
+            }
+            _ => {
+                // other device keys (private_key, fwmark...) are not used for now
+                let peer = match status.peers.last_mut() {
+                    Some(peer) => peer,
+                    None => continue,
+                };
+                match key {
+                    "endpoint" => {
+                        peer.endpoint = Some(value.parse().map_err(|_| invalid(line))?);
+                    }
+                    "last_handshake_time_sec" => {
+                        let sec = value.parse::<u64>().map_err(|_| invalid(line))?;
+                        // 0 means no handshake yet
+                        peer.last_handshake = match sec {
+                            0 => None,
+                            _ => Some(SystemTime::UNIX_EPOCH + time::Duration::from_secs(sec)),
+                        };
+                    }
+                    "last_handshake_time_nsec" => {
+                        let nsec = value.parse::<u64>().map_err(|_| invalid(line))?;
+                        if let Some(t) = peer.last_handshake {
+                            peer.last_handshake = Some(t + time::Duration::from_nanos(nsec));
+                        }
+                    }
+                    "rx_bytes" => peer.rx_bytes = value.parse().map_err(|_| invalid(line))?,
+                    "tx_bytes" => peer.tx_bytes = value.parse().map_err(|_| invalid(line))?,
+                    "persistent_keepalive_interval" => {
+                        peer.keepalive = value.parse().map_err(|_| invalid(line))?
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    // errno is the last line, a response cut before it may miss peers or their handshakes
+    if !complete {
+        return Err(Error::InvalidResponse(
+            "response ends before errno, unable to determine status".to_string(),
+        ));
+    }
+    Ok(status)
+}
+
+// uapi only accepts ip:port, so the host of peer_address is resolved every
+// time the config is built, picking up dns changes on reconnect.
+// this may block on dns, see resolve_endpoints
+fn resolve_endpoint(endpoint: &str) -> Result<SocketAddr, Error> {
+    if let Ok(addr) = endpoint.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let resolve_err = |msg: String| {
+        Error::InvalidConfig(format!("failed to resolve endpoint {}: {}", endpoint, msg))
+    };
+    // the port can not be told apart from an ipv6 address like 2001:db8::1:8000
+    if endpoint.parse::<IpAddr>().is_ok() || !endpoint.contains(':') {
+        return Err(resolve_err(
+            "no port found, it should be like host:port or [ipv6]:port".to_string(),
+        ));
+    }
+    // a bare ipv6 address with a port that is not valid hex, like 2001:db8::1:51820
+    if let Some((host, port)) = endpoint.rsplit_once(':') {
+        if let (Ok(host), Ok(port)) = (host.parse::<Ipv6Addr>(), port.parse::<u16>()) {
+            let addr = SocketAddr::from((host, port));
+            log::warn!(
+                "endpoint {} is taken as {}, please bracket it",
+                endpoint,
+                addr
+            );
+            return Ok(addr);
+        }
+    }
+    let addr = endpoint
+        .to_socket_addrs()
+        .map_err(|e| resolve_err(e.to_string()))?
+        .next()
+        .ok_or_else(|| resolve_err("no address found".to_string()))?;
+    log::info!("resolve endpoint {} to {}", endpoint, addr);
+    Ok(addr)
+}
+
+// resolve_endpoint on the blocking pool, in the same order
+pub async fn resolve_endpoints(endpoints: Vec<String>) -> Result<Vec<SocketAddr>, Error> {
+    tokio::task::spawn_blocking(move || {
+        endpoints
+            .iter()
+            .map(|endpoint| resolve_endpoint(endpoint))
+            .collect()
+    })
+    .await
+    .map_err(|e| Error::Io(io::Error::other(e)))?
+}
+
+fn prepare_routes(routes: &[ipnet::IpNet], excludes: &[ipnet::IpNet]) -> Vec<ipnet::IpNet> {
+    let routes = route::exclude_routes(routes, excludes);
+    let collapsed = route::collapse_routes(&routes);
+    if collapsed.len() < routes.len() {
+        log::info!(
+            "collapsed {} duplicated or overlapping route(s)",
+            routes.len() - collapsed.len()
+        );
+    }
+    collapsed
+}
+
+// in cidr form, the main address first
+pub fn addresses(conf: &config::WgConf) -> Vec<String> {
+    let mut addrs = vec![format!("{}/{}", conf.address, conf.mask)];
+    addrs.extend(conf.extra_address.iter().cloned());
+    addrs
+}
+
+// the system routes follow allowed ips unless given separately
+pub fn system_routes(conf: &config::WgConf) -> Result<Vec<ipnet::IpNet>, Error> {
+    let excludes = &conf.exclude_route;
+    let routes = match &conf.routes {
+        Some(routes) => prepare_routes(routes, excludes),
+        None => {
+            let allowed_ips: Vec<ipnet::IpNet> = conf
+                .peers
+                .iter()
+                .flat_map(|peer| route::exclude_routes(&peer.allowed_ips, excludes))
+                .collect();
+            route::collapse_routes(&allowed_ips)
+        }
+    };
+    if !conf.exclude_own_subnet {
+        return Ok(routes);
+    }
+    // the subnet of the interface is reachable directly, do not route it into the tunnel
+    let own_subnets: Vec<ipnet::IpNet> = route::parse_routes(&addresses(conf))?
+        .into_iter()
+        .filter(|net| net.prefix_len() < net.max_prefix_len())
+        .map(|net| net.trunc())
+        .collect();
+    Ok(route::exclude_routes(&routes, &own_subnets))
+}
+
+// build the set=1 request for uapi from wg conf,
+// with the endpoints of the peers resolved by resolve_endpoints
+pub fn build_uapi_config(conf: &config::WgConf, endpoints: &[SocketAddr]) -> Result<String, Error> {
+    conf.validate()
+        .map_err(|e| Error::InvalidConfig(format!("invalid wg config: {}", e)))?;
+    let excludes = &conf.exclude_route;
+    let private_key = key_to_hex("private_key", &conf.private_key)?;
+
+    let mut buff = String::from("set=1\n");
+    // standard wg-go uapi operations
+    // see https://www.wireguard.com/xplatform/#configuration-protocol
+    buff.push_str(format!("private_key={private_key}\n").as_str());
+    if let Some(listen_port) = conf.listen_port {
+        buff.push_str(format!("listen_port={listen_port}\n").as_str());
+    }
+    if let Some(fwmark) = conf.fwmark {
+        #[cfg(not(target_os = "linux"))]
+        log::warn!(
+            "fwmark is only supported on linux, {} may be ignored",
+            fwmark
+        );
+        buff.push_str(format!("fwmark={fwmark}\n").as_str());
+    }
+    if conf.replace {
+        // traffic is interrupted briefly if the interface is already up
+        log::warn!("replace all peers and allowed ips of the interface");
         buff.push_str("replace_peers=true\n".to_string().as_str());
+    }
+    if endpoints.len() != conf.peers.len() {
+        return Err(Error::InvalidConfig(format!(
+            "{} endpoint(s) resolved for {} peer(s)",
+            endpoints.len(),
+            conf.peers.len()
+        )));
+    }
+    for (peer, endpoint) in conf.peers.iter().zip(endpoints) {
+        let keepalive = peer.keepalive.unwrap_or(DEFAULT_KEEPALIVE);
+        // persistent_keepalive_interval is a 16-bit value in uapi, 0 means disabled
+        if keepalive > u16::MAX as u32 {
+            return Err(Error::InvalidConfig(format!(
+                "keepalive {} is out of range 0-{}",
+                keepalive,
+                u16::MAX
+            )));
+        }
+        let public_key = key_to_hex("peer_key", &peer.public_key)?;
+        let preshared_key = match &peer.preshared_key {
+            Some(key) => Some(key_to_hex("preshared_key", key)?),
+            None => None,
+        };
+        let fingerprint = utils::key_fingerprint(&peer.public_key);
+        log::info!("configure peer {} at {}", fingerprint, endpoint);
+        let allowed_ips = prepare_routes(&peer.allowed_ips, excludes);
+
         buff.push_str(format!("public_key={public_key}\n").as_str());
-        buff.push_str("replace_allowed_ips=true\n".to_string().as_str());
-        buff.push_str(format!("endpoint={}\n", conf.peer_address).as_str());
-        buff.push_str("persistent_keepalive_interval=10\n".to_string().as_str());
-        for route in &conf.route {
+        if let Some(preshared_key) = preshared_key {
+            buff.push_str(format!("preshared_key={preshared_key}\n").as_str());
+        }
+        if conf.replace {
+            buff.push_str("replace_allowed_ips=true\n".to_string().as_str());
+        }
+        buff.push_str(format!("endpoint={endpoint}\n").as_str());
+        if conf.roaming {
+            buff.push_str(format!("persistent_keepalive_interval={keepalive}\n").as_str());
+        } else {
+            log::info!("not roaming, skip persistent keepalive");
+        }
+        for route in &allowed_ips {
             buff.push_str(format!("allowed_ip={route}\n").as_str());
         }
+    }
 
-        // wg-corplink uapi operations
-        let addr = format!("{}/{}", conf.address, conf.mask);
-        let mtu = conf.mtu;
+    // wg-corplink uapi operations
+    let addrs = addresses(conf);
+    // 0 is replaced with the detected one by resolve_mtu beforehand
+    let mtu = match conf.mtu {
+        0 => mtu::DEFAULT_MTU,
+        mtu => mtu,
+    };
+    for addr in &addrs {
         buff.push_str(format!("address={addr}\n").as_str());
-        buff.push_str(format!("mtu={mtu}\n").as_str());
-        buff.push_str("up=true\n".to_string().as_str());
-        for route in &conf.route {
+    }
+    buff.push_str(format!("mtu={mtu}\n").as_str());
+    buff.push_str("up=true\n".to_string().as_str());
+    let routes = system_routes(conf)?;
+    if !conf.manage_routes {
+        log::info!(
+            "route management is disabled, skip installing {} route(s)",
+            routes.len()
+        );
+    } else if let Some(table) = conf.route_table.filter(|_| cfg!(target_os = "linux")) {
+        // installed by corplink-rs later, uapi only knows about the main table
+        log::info!("install {} route(s) into table {}", routes.len(), table);
+    } else {
+        #[cfg(not(target_os = "linux"))]
+        if let Some(table) = conf.route_table {
+            log::warn!(
+                "route_table is only supported on linux, {} is ignored",
+                table
+            );
+        }
+        // the endpoints keep going through the default route of the system
+        let endpoint_routes: Vec<ipnet::IpNet> = endpoints
+            .iter()
+            .map(|e| ipnet::IpNet::from(e.ip()))
+            .collect();
+        let routes = route::split_default_routes(&routes);
+        for route in &route::exclude_routes(&routes, &endpoint_routes) {
             buff.push_str(format!("route={route}\n").as_str());
         }
-        // end operation
+    }
+    // end operation
 
-        buff.push('\n');
-        log::info!("send config to uapi");
-        let data = uapi(buff.as_bytes());
-        let s = String::from_utf8(data).unwrap();
-        if !s.contains("errno=0") {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                format!("uapi returns unexpected result: {}", s),
-            ));
-        }
+    buff.push('\n');
+    Ok(buff)
+}
+
+// 0 means detect automatically, from the path to the first peer,
+// probing waits for icmp, so it is run on the blocking pool
+async fn resolve_mtu(mtu: u32, endpoint: Option<SocketAddr>) -> Result<u32, Error> {
+    let endpoint = match (mtu, endpoint) {
+        (0, Some(endpoint)) => endpoint,
+        (0, None) => return Ok(mtu::DEFAULT_MTU),
+        (mtu, _) => return Ok(mtu),
+    };
+    tokio::task::spawn_blocking(move || mtu::detect_mtu(&endpoint))
+        .await
+        .map_err(|e| Error::Io(io::Error::other(e)))
+}
+
+// resolve the endpoints and the mtu, then build the config with them
+pub async fn prepare_uapi_config(conf: &config::WgConf) -> Result<String, Error> {
+    let endpoints = conf.peers.iter().map(|peer| peer.endpoint.clone());
+    let endpoints = resolve_endpoints(endpoints.collect()).await?;
+    let mut conf = conf.clone();
+    conf.mtu = resolve_mtu(conf.mtu, endpoints.first().copied()).await?;
+    build_uapi_config(&conf, &endpoints)
+}
+
+// update the endpoints of the existing peers only, their hosts are resolved
+// again, so that a dns change can be picked up without restarting the device
+async fn build_uapi_endpoints<'a>(
+    peers: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Result<String, Error> {
+    let (keys, endpoints): (Vec<&str>, Vec<String>) = peers
+        .into_iter()
+        .map(|(peer_key, endpoint)| (peer_key, endpoint.to_string()))
+        .unzip();
+    let endpoints = resolve_endpoints(endpoints).await?;
+    let mut buff = String::from("set=1\n");
+    for (peer_key, endpoint) in keys.into_iter().zip(endpoints) {
+        let public_key = key_to_hex("peer_key", peer_key)?;
+        buff.push_str(format!("public_key={public_key}\n").as_str());
+        buff.push_str("update_only=true\n".to_string().as_str());
+        buff.push_str(format!("endpoint={endpoint}\n").as_str());
+    }
+    buff.push('\n');
+    Ok(buff)
+}
+
+fn set_lock(name: &str) -> Arc<tokio::sync::Mutex<()>> {
+    static SET_LOCKS: OnceLock<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+        OnceLock::new();
+    let mut locks = SET_LOCKS.get_or_init(Default::default).lock().unwrap();
+    locks.entry(name.to_string()).or_default().clone()
+}
+
+#[derive(Clone)]
+pub struct UAPIClient {
+    pub name: String,
+    // deadline of a whole uapi exchange
+    pub timeout: time::Duration,
+    pub events: Events,
+    pub backend: Arc<dyn WgBackend>,
+}
+
+impl UAPIClient {
+    // uapi blocks until wg-corplink responds, run it off the runtime so that
+    // a stalled wg-corplink can not hang us forever
+    async fn request(&self, buff: Vec<u8>) -> Result<String, Error> {
+        let backend = self.backend.clone();
+        let task = tokio::task::spawn_blocking(move || backend.uapi(&buff));
+        let data = match tokio::time::timeout(self.timeout, task).await {
+            Ok(data) => data.map_err(|e| Error::Io(io::Error::other(e)))?,
+            Err(_) => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "uapi of {} does not respond in {}s",
+                        self.name,
+                        self.timeout.as_secs()
+                    ),
+                )))
+            }
+        };
+        String::from_utf8(data).map_err(|e| Error::InvalidResponse(e.to_string()))
+    }
+
+    // set=1 requests of an interface are sent one at a time, so that the clones
+    // of a client, e.g. in a reconnect and the monitor, do not interleave
+    async fn set(&self, buff: String) -> Result<(), Error> {
+        let lock = set_lock(&self.name);
+        let _guard = lock.lock().await;
+        let s = self.request(buff.into_bytes()).await?;
+        parse_errno(&s)
+    }
+
+    pub async fn config_wg(&mut self, conf: &config::WgConf) -> Result<(), Error> {
+        let buff = prepare_uapi_config(conf).await?;
+        log::debug!("send config to uapi");
+        self.set(buff).await?;
         Ok(())
     }
 
-    pub async fn check_wg_connection(&mut self) {
-        // default refresh key timeout of wg is 2 min
-        // we set wg connection timeout to 5 min
-        let interval = time::Duration::from_secs(5 * 60);
-        let mut ticker = tokio::time::interval(interval);
-        let mut timeout = false;
+    // the counterpart of up=true in config_wg, the routes of the interface are
+    // removed by the system together with its addresses
+    pub async fn bring_down(&mut self) -> Result<(), Error> {
+        log::info!("bring down {}", self.name);
+        self.set("set=1\nup=false\n\n".to_string()).await
+    }
+
+    // indices of the peers still without a handshake when the timeout is reached
+    async fn pending_handshakes(
+        &self,
+        conf: &config::WgConf,
+        timeout: time::Duration,
+    ) -> Result<Vec<usize>, Error> {
+        let mut keys = Vec::new();
+        for peer in &conf.peers {
+            keys.push(key_to_hex("peer_key", &peer.public_key)?);
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut ticker = tokio::time::interval(time::Duration::from_millis(500));
+        loop {
+            ticker.tick().await;
+            let status = self.get_status().await?;
+            let pending: Vec<usize> = (0..keys.len())
+                .filter(|&i| {
+                    !status
+                        .peers
+                        .iter()
+                        .any(|peer| peer.public_key == keys[i] && peer.last_handshake.is_some())
+                })
+                .collect();
+            if pending.is_empty() || tokio::time::Instant::now() >= deadline {
+                return Ok(pending);
+            }
+        }
+    }
+
+    // errno=0 of config_wg only means the config is accepted,
+    // true if any peer has a handshake in the end. a peer without a handshake
+    // within the timeout is pointed to its next fallback endpoint, and the one
+    // that works is swapped into conf, so that recover refreshes it instead
+    pub async fn wait_for_handshake(
+        &mut self,
+        conf: &mut config::WgConf,
+        timeout: time::Duration,
+    ) -> Result<bool, Error> {
+        let mut round = 0;
+        loop {
+            let pending = self.pending_handshakes(conf, timeout).await?;
+            let mut switched = Vec::new();
+            for &i in &pending {
+                let peer = &mut conf.peers[i];
+                if round >= peer.fallback_endpoints.len() {
+                    continue;
+                }
+                log::warn!(
+                    "no handshake of peer {} through {} in {}s, try {}",
+                    utils::key_fingerprint(&peer.public_key),
+                    peer.endpoint,
+                    timeout.as_secs(),
+                    peer.fallback_endpoints[round]
+                );
+                // the candidates are kept in order, with the failed one in place of the next
+                std::mem::swap(&mut peer.endpoint, &mut peer.fallback_endpoints[round]);
+                switched.push(i);
+            }
+            if switched.is_empty() {
+                for (i, peer) in conf.peers.iter().enumerate() {
+                    if round > 0 && !pending.contains(&i) && !peer.fallback_endpoints.is_empty() {
+                        let key = utils::key_fingerprint(&peer.public_key);
+                        log::info!("peer {} handshakes through {}", key, peer.endpoint);
+                    }
+                }
+                return Ok(pending.len() < conf.peers.len());
+            }
+            let peers = switched.iter().map(|&i| {
+                let peer = &conf.peers[i];
+                (peer.public_key.as_str(), peer.endpoint.as_str())
+            });
+            let buff = build_uapi_endpoints(peers).await?;
+            self.set(buff).await?;
+            round += 1;
+        }
+    }
+
+    // reads do not change the device, so clones of a client can run them side by side
+    pub async fn get_status(&self) -> Result<WgStatus, Error> {
+        let s = self.request(b"get=1\n\n".to_vec()).await?;
+        parse_status(&s)
+    }
+
+    // only the device key is changed, peers are kept and handshake again with the new key
+    #[allow(dead_code)]
+    pub async fn rotate_private_key(&mut self, new_key: &str) -> Result<(), Error> {
+        let private_key = key_to_hex("private_key", new_key)?;
+        log::info!("rotate private key of {}", self.name);
+        let buff = format!("set=1\nprivate_key={private_key}\n\n");
+        self.set(buff).await
+    }
+
+    // point a peer to a new endpoint, e.g. after the network changes,
+    // a peer not configured yet is not added
+    #[allow(dead_code)]
+    pub async fn refresh_endpoint(&mut self, peer_key: &str, endpoint: &str) -> Result<(), Error> {
+        let fingerprint = utils::key_fingerprint(peer_key);
+        log::info!("refresh endpoint of peer {} to {}", fingerprint, endpoint);
+        let buff = build_uapi_endpoints([(peer_key, endpoint)]).await?;
+        self.set(buff).await
+    }
+
+    // the light path before restarting the device on a stale handshake,
+    // true if a new handshake happens within the recover window
+    pub async fn recover(
+        &mut self,
+        conf: &config::WgConf,
+        check_conf: &CheckConfig,
+    ) -> Result<bool, Error> {
+        let start = SystemTime::now();
+        log::info!("refresh endpoints of {}", self.name);
+        let peers = conf
+            .peers
+            .iter()
+            .map(|peer| (peer.public_key.as_str(), peer.endpoint.as_str()));
+        let buff = build_uapi_endpoints(peers).await?;
+        self.set(buff).await?;
+
+        let deadline = tokio::time::Instant::now() + time::Duration::from_secs(RECOVER_WINDOW);
+        let mut ticker = tokio::time::interval(time::Duration::from_secs(1));
+        while tokio::time::Instant::now() < deadline {
+            ticker.tick().await;
+            let status = self.get_status().await?;
+            if let Some((_, t)) = check_conf.last_handshake(&status) {
+                if t >= start {
+                    log::info!(
+                        "handshake of {} is back after refreshing endpoints",
+                        self.name
+                    );
+                    return Ok(true);
+                }
+            }
+        }
+        log::warn!(
+            "no handshake of {} in {}s after refreshing endpoints",
+            self.name,
+            RECOVER_WINDOW
+        );
+        Ok(false)
+    }
+
+    #[allow(dead_code)]
+    pub async fn get_transfer(&self) -> Result<(u64, u64), Error> {
+        Ok(self.get_status().await?.transfer())
+    }
+
+    pub async fn check_wg_connection(
+        &mut self,
+        check_conf: &CheckConfig,
+        cancel: &mut oneshot::Receiver<()>,
+    ) -> Result<ConnectionStatus, Error> {
+        let timeout = check_conf.stale_timeout;
+        let detection = check_conf.stale_detection;
+        let mut ticker = tokio::time::interval(check_conf.poll_interval);
+        let mut last_transfer = None;
+        let mut last_progress = time::Instant::now();
+        // a peer never handshaking is stale once the timeout has passed since then
+        let checking_since = time::Instant::now();
+        // the degrading event is published once until the handshake is fresh again
+        let mut degrading = false;
         // consume the first tick
         ticker.tick().await;
-        while !timeout {
-            ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {},
+                // a dropped sender cancels as well
+                _ = &mut *cancel => {
+                    log::info!("stop checking connection of {}", self.name);
+                    return Ok(ConnectionStatus::Cancelled);
+                }
+            }
 
+            // uapi keeps answering after the tun device is deleted, e.g. by ip link del
+            #[cfg(unix)]
+            if !interface_exists(&self.name) {
+                log::error!("interface {} is gone", self.name);
+                return Ok(ConnectionStatus::DeviceGone);
+            }
+            let status = self.get_status().await?;
             let name = self.name.as_str();
-            let data = uapi(b"get=1\n\n");
-            let s = String::from_utf8(data).unwrap();
-            for line in s.split('\n') {
-                if line.starts_with("last_handshake_time_sec") {
-                    match line.trim_end().split('=').last().unwrap().parse::<i64>() {
-                        Ok(timestamp) => {
-                            if timestamp == 0 {
-                                // do nothing because it's invalid
-                            } else {
-                                let nt =
-                                    chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0)
-                                        .unwrap();
-                                let now = chrono::Utc::now().naive_utc();
-                                let t = now - nt;
-                                let tt: chrono::DateTime<chrono::Utc> =
-                                    chrono::DateTime::from_utc(nt, chrono::Utc);
-                                let lt = tt.with_timezone(&chrono::Local);
-                                let elapsed = t.to_std().unwrap().as_secs_f32();
-                                log::info!(
-                                    "last handshake is at {lt}, elapsed time {elapsed}s"
-                                );
-                                if t > chrono::Duration::from_std(interval).unwrap() {
-                                    log::warn!(
-                                        "last handshake is at {}, elapsed time {}s more than {}s",
-                                        lt,
-                                        elapsed,
-                                        interval.as_secs()
-                                    );
-                                    timeout = true;
-                                }
-                            }
-                        }
-                        Err(err) => {
-                            log::warn!("parse last handshake of {} fail: {}", name, err)
-                        }
+            let (rx, tx) = status.transfer();
+            // without keepalive, handshakes lapse on an idle tunnel,
+            // which is not a broken one
+            let idle = last_transfer == Some((rx, tx));
+            if let Some((last_rx, last_tx)) = last_transfer {
+                // counters are reset if the peer is reconfigured
+                let rx_delta = rx.saturating_sub(last_rx);
+                let tx_delta = tx.saturating_sub(last_tx);
+                let secs = check_conf.poll_interval.as_secs_f64();
+                log::log!(
+                    check_conf.healthy_level(log::Level::Info),
+                    "transfer of {} since last check: rx {} bytes ({:.1} B/s), tx {} bytes ({:.1} B/s)",
+                    name,
+                    rx_delta,
+                    rx_delta as f64 / secs,
+                    tx_delta,
+                    tx_delta as f64 / secs
+                );
+            }
+            last_transfer = Some((rx, tx));
+            if !idle {
+                last_progress = time::Instant::now();
+            }
+            if status.peers.is_empty() {
+                log::warn!("no peer found on {}", name);
+                return Ok(ConnectionStatus::Stopped);
+            }
+            if detection != config::StaleDetection::Time {
+                let stalled = last_progress.elapsed();
+                if stalled > timeout {
+                    self.events.publish(EventKind::HandshakeStale {
+                        interface: self.name.clone(),
+                        elapsed: stalled,
+                    });
+                    log::error!(
+                        "no traffic on {} for {}s, more than {}s",
+                        name,
+                        stalled.as_secs(),
+                        timeout.as_secs()
+                    );
+                    return Ok(ConnectionStatus::Timeout);
+                }
+                if detection == config::StaleDetection::Bytes {
+                    log::log!(
+                        check_conf.healthy_level(log::Level::Debug),
+                        "traffic of {} is flowing, skip checking handshake",
+                        name
+                    );
+                    continue;
+                }
+            }
+            let handshake = check_conf.last_handshake(&status);
+            match handshake {
+                None => {
+                    let waited = checking_since.elapsed();
+                    if waited > timeout {
+                        self.events.publish(EventKind::HandshakeStale {
+                            interface: self.name.clone(),
+                            elapsed: waited,
+                        });
+                        log::error!(
+                            "no handshake of {} in {}s of checking, more than {}s",
+                            name,
+                            waited.as_secs(),
+                            timeout.as_secs()
+                        );
+                        return Ok(ConnectionStatus::Timeout);
                     }
-                    break;
-                } else if line.starts_with("errno") {
-                    if line != "errno=0" {
-                        log::warn!("uapi of {} return: fail: {}", name, line)
+                    log::debug!("no handshake of {} to check yet", name);
+                }
+                Some((peer, last_handshake)) => {
+                    let handshake_time = chrono::DateTime::<chrono::Utc>::from(last_handshake);
+                    let lt = handshake_time.with_timezone(&chrono::Local);
+                    // the handshake is in the future if the clock is adjusted backwards,
+                    // treat it as fresh
+                    let elapsed = (chrono::Utc::now() - handshake_time)
+                        .to_std()
+                        .unwrap_or_default();
+                    let peer_key = &peer.fingerprint;
+                    // an idle tunnel is judged by the bytes check if it is enabled
+                    if elapsed > timeout && idle && detection == config::StaleDetection::Time {
+                        log::log!(
+                            check_conf.healthy_level(log::Level::Info),
+                            "no traffic on {} since last check, keep the idle tunnel",
+                            name
+                        );
+                    } else if elapsed > timeout {
+                        self.events.publish(EventKind::HandshakeStale {
+                            interface: self.name.clone(),
+                            elapsed,
+                        });
+                        log::error!(
+                            "last handshake of peer {} is at {}, elapsed time {}s more than {}s",
+                            peer_key,
+                            lt,
+                            elapsed.as_secs_f32(),
+                            timeout.as_secs()
+                        );
+                        return Ok(ConnectionStatus::Timeout);
+                    } else if elapsed > timeout.mul_f64(check_conf.degrade_ratio) {
+                        // early warning that the tunnel might be degrading
+                        log::warn!(
+                            "last handshake of peer {} is at {}, elapsed time {}s more than {}% of {}s",
+                            peer_key,
+                            lt,
+                            elapsed.as_secs_f32(),
+                            (check_conf.degrade_ratio * 100.0).round(),
+                            timeout.as_secs()
+                        );
+                        if !degrading {
+                            degrading = true;
+                            self.events.publish(EventKind::HandshakeDegrading {
+                                interface: self.name.clone(),
+                                elapsed,
+                                timeout,
+                            });
+                        }
+                    } else {
+                        degrading = false;
+                        log::log!(
+                            check_conf.healthy_level(log::Level::Debug),
+                            "last handshake of peer {} is at {}, elapsed time {}s",
+                            peer_key,
+                            lt,
+                            elapsed.as_secs_f32()
+                        );
                     }
-                } else if line.is_empty() {
-                    // reach end
-                    break;
                 }
             }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bytes 0-31, 32-63 and 64-95
+    const PRIVATE_KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const PEER_KEY: &str = "ICEiIyQlJicoKSorLC0uLzAxMjM0NTY3ODk6Ozw9Pj8=";
+    const PUBLIC_KEY: &str = "QEFCQ0RFRkdISUpLTE1OT1BRUlNUVVZXWFlaW1xdXl8=";
+    const PEER_KEY_HEX: &str = "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f";
+
+    // an ip endpoint and a fixed mtu, so that nothing goes to the network
+    fn sample_conf(allowed_ips: &[&str]) -> config::WgConf {
+        let allowed_ips = allowed_ips.iter().map(|net| net.parse().unwrap()).collect();
+        config::WgConf {
+            address: "10.0.0.2".to_string(),
+            mask: 24,
+            extra_address: Vec::new(),
+            vpn_name: String::new(),
+            mtu: 1400,
+            public_key: PUBLIC_KEY.to_string(),
+            private_key: PRIVATE_KEY.to_string(),
+            peers: vec![config::Peer::new(
+                PEER_KEY.to_string(),
+                "1.2.3.4:51820".to_string(),
+                allowed_ips,
+            )],
+            routes: None,
+            exclude_route: Vec::new(),
+            manage_routes: true,
+            exclude_own_subnet: true,
+            replace: true,
+            roaming: true,
+            fwmark: None,
+            route_table: None,
+            listen_port: None,
+            dns: Vec::new(),
+            protocol: config::Protocol::Udp,
+            api_endpoints: Vec::new(),
+        }
+    }
+
+    // the sample endpoints are ip:port, so this does not block
+    fn sample_endpoints(conf: &config::WgConf) -> Vec<SocketAddr> {
+        conf.peers
+            .iter()
+            .map(|peer| resolve_endpoint(&peer.endpoint).unwrap())
+            .collect()
+    }
+
+    fn mock_client(status: &str) -> (UAPIClient, Arc<MockBackend>) {
+        let backend = Arc::new(MockBackend::new(status));
+        let uapi = UAPIClient {
+            name: "corplink-test".to_string(),
+            timeout: time::Duration::from_secs(1),
+            events: Events::new(),
+            backend: backend.clone(),
+        };
+        (uapi, backend)
+    }
+
+    #[test]
+    fn test_build_uapi_config() {
+        let conf = sample_conf(&["10.1.0.0/16", "192.168.0.0/16"]);
+        let buff = build_uapi_config(&conf, &sample_endpoints(&conf)).unwrap();
+        let lines: Vec<&str> = buff.lines().collect();
+        let private_key: String = (0..32u8).map(|b| format!("{b:02x}")).collect();
+        assert_eq!(
+            lines,
+            [
+                "set=1".to_string(),
+                format!("private_key={private_key}"),
+                "replace_peers=true".to_string(),
+                format!("public_key={PEER_KEY_HEX}"),
+                "replace_allowed_ips=true".to_string(),
+                "endpoint=1.2.3.4:51820".to_string(),
+                "persistent_keepalive_interval=10".to_string(),
+                "allowed_ip=10.1.0.0/16".to_string(),
+                "allowed_ip=192.168.0.0/16".to_string(),
+                "address=10.0.0.2/24".to_string(),
+                "mtu=1400".to_string(),
+                "up=true".to_string(),
+                "route=10.1.0.0/16".to_string(),
+                "route=192.168.0.0/16".to_string(),
+                // the blank line ends the request
+                String::new(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_uapi_config_bare_ip_route() {
+        let mut conf = sample_conf(&["10.1.0.0/16"]);
+        let routes = ["10.1.2.3", "fd00::1", "192.168.0.0/16"].map(String::from);
+        conf.routes = Some(route::parse_routes(&routes).unwrap());
+        let buff = build_uapi_config(&conf, &sample_endpoints(&conf)).unwrap();
+        let lines: Vec<&str> = buff.lines().collect();
+        let private_key: String = (0..32u8).map(|b| format!("{b:02x}")).collect();
+        assert_eq!(
+            lines,
+            [
+                "set=1".to_string(),
+                format!("private_key={private_key}"),
+                "replace_peers=true".to_string(),
+                format!("public_key={PEER_KEY_HEX}"),
+                "replace_allowed_ips=true".to_string(),
+                "endpoint=1.2.3.4:51820".to_string(),
+                "persistent_keepalive_interval=10".to_string(),
+                "allowed_ip=10.1.0.0/16".to_string(),
+                "address=10.0.0.2/24".to_string(),
+                "mtu=1400".to_string(),
+                "up=true".to_string(),
+                // a single host gets the full prefix
+                "route=10.1.2.3/32".to_string(),
+                "route=fd00::1/128".to_string(),
+                "route=192.168.0.0/16".to_string(),
+                String::new(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_uapi_config_no_routes() {
+        let conf = sample_conf(&[]);
+        let buff = build_uapi_config(&conf, &sample_endpoints(&conf)).unwrap();
+        let lines: Vec<&str> = buff.lines().collect();
+        let private_key: String = (0..32u8).map(|b| format!("{b:02x}")).collect();
+        // the interface is still brought up, just without routes
+        assert_eq!(
+            lines,
+            [
+                "set=1".to_string(),
+                format!("private_key={private_key}"),
+                "replace_peers=true".to_string(),
+                format!("public_key={PEER_KEY_HEX}"),
+                "replace_allowed_ips=true".to_string(),
+                "endpoint=1.2.3.4:51820".to_string(),
+                "persistent_keepalive_interval=10".to_string(),
+                "address=10.0.0.2/24".to_string(),
+                "mtu=1400".to_string(),
+                "up=true".to_string(),
+                String::new(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_uapi_config_default_route() {
+        let conf = sample_conf(&["0.0.0.0/0"]);
+        let buff = build_uapi_config(&conf, &sample_endpoints(&conf)).unwrap();
+        let routes: Vec<ipnet::IpNet> = buff
+            .lines()
+            .filter_map(|line| line.strip_prefix("route="))
+            .map(|route| route.parse().unwrap())
+            .collect();
+        assert!(buff.contains("allowed_ip=0.0.0.0/0\n"));
+        assert!(routes.iter().all(|route| route.prefix_len() > 0));
+        // the endpoint and the own subnet are left to the system routes
+        let endpoint: IpAddr = "1.2.3.4".parse().unwrap();
+        let own: IpAddr = "10.0.0.7".parse().unwrap();
+        assert!(!routes.iter().any(|route| route.contains(&endpoint)));
+        assert!(!routes.iter().any(|route| route.contains(&own)));
+        assert!(routes.contains(&"128.0.0.0/1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_build_uapi_config_invalid() {
+        let mut conf = sample_conf(&["10.1.0.0/16"]);
+        conf.peers[0].public_key = "invalid".to_string();
+        assert!(matches!(
+            build_uapi_config(&conf, &sample_endpoints(&conf)),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    // what is configured is read back the same through get=1
+    #[tokio::test]
+    async fn test_uapi_config_round_trip() {
+        let mut conf = sample_conf(&["10.1.0.0/16"]);
+        conf.listen_port = Some(51821);
+        conf.peers[0].keepalive = Some(25);
+        let buff = build_uapi_config(&conf, &sample_endpoints(&conf)).unwrap();
+        // a get=1 response is the device and peer keys followed by errno
+        let response = buff.trim_start_matches("set=1\n").trim_end().to_string() + "\nerrno=0\n\n";
+        let (uapi, _) = mock_client(&response);
+
+        let status = uapi.get_status().await.unwrap();
+        assert_eq!(status.listen_port, Some(51821));
+        assert_eq!(status.peers.len(), 1);
+        let peer = &status.peers[0];
+        assert_eq!(peer.public_key, PEER_KEY_HEX);
+        assert_eq!(peer.fingerprint, utils::key_fingerprint(PEER_KEY));
+        assert_eq!(peer.endpoint, Some("1.2.3.4:51820".parse().unwrap()));
+        assert_eq!(peer.keepalive, 25);
+        assert!(peer.last_handshake.is_none());
+    }
+
+    // wg-corplink closes the response early, before errno
+    #[tokio::test]
+    async fn test_get_status_truncated() {
+        let truncated = format!("listen_port=51821\npublic_key={PEER_KEY_HEX}\nrx_bytes=1024\n");
+        for response in [truncated.as_str(), ""] {
+            let (uapi, _) = mock_client(response);
+            match uapi.get_status().await {
+                Err(Error::InvalidResponse(msg)) => {
+                    assert!(msg.contains("unable to determine status"), "{}", msg)
+                }
+                _ => panic!("truncated response {:?} is accepted", response),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_config_wg() {
+        let conf = sample_conf(&["10.1.0.0/16"]);
+        let (mut uapi, backend) = mock_client("errno=0\n\n");
+        uapi.config_wg(&conf).await.unwrap();
+        uapi.bring_down().await.unwrap();
+        let calls = backend.calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(
+            calls[0],
+            build_uapi_config(&conf, &sample_endpoints(&conf)).unwrap()
+        );
+        assert_eq!(calls[1], "set=1\nup=false\n\n");
+    }
+
+    #[test]
+    fn test_protocol_mode() {
+        for protocol in [config::Protocol::Tcp, config::Protocol::Udp] {
+            assert_eq!(config::Protocol::try_from(protocol.mode()), Ok(protocol));
+        }
+        assert_eq!(config::Protocol::try_from(2), Ok(config::Protocol::Udp));
+        assert!(config::Protocol::try_from(0).is_err());
+        assert!(config::Protocol::try_from(0xff).is_err());
+    }
+
+    // tcp is refused before wg-corplink is started
+    #[test]
+    fn test_start_tcp_refused() {
+        assert!(check_protocol(config::Protocol::Tcp).is_err());
+        assert!(check_protocol(config::Protocol::Udp).is_ok());
+
+        let backend = MockBackend::new("");
+        assert!(backend.start("corplink-test", config::Protocol::Udp, false));
+        assert_eq!(backend.calls(), ["start corplink-test udp"]);
+    }
+
+    #[test]
+    fn test_last_handshake_policy() {
+        let at = |sec| Some(SystemTime::UNIX_EPOCH + time::Duration::from_secs(sec));
+        let peer = |key: &str, last_handshake| PeerStatus {
+            public_key: key.to_string(),
+            last_handshake,
+            ..Default::default()
+        };
+        let mut check_conf = CheckConfig {
+            poll_interval: time::Duration::from_secs(1),
+            stale_timeout: time::Duration::from_secs(1),
+            handshake_policy: config::HandshakePolicy::Any,
+            stale_detection: config::StaleDetection::Time,
+            degrade_ratio: DEFAULT_DEGRADE_RATIO,
+            quiet: false,
+        };
+        let status = WgStatus {
+            listen_port: None,
+            peers: vec![peer("a", at(100)), peer("b", at(200))],
+        };
+        let key = |check_conf: &CheckConfig, status| {
+            check_conf
+                .last_handshake(status)
+                .map(|(peer, _)| peer.public_key.clone())
+        };
+        assert_eq!(key(&check_conf, &status).as_deref(), Some("b"));
+        check_conf.handshake_policy = config::HandshakePolicy::All;
+        assert_eq!(key(&check_conf, &status).as_deref(), Some("a"));
+
+        // a peer without handshake holds the all policy back
+        let status = WgStatus {
+            listen_port: None,
+            peers: vec![peer("a", at(100)), peer("b", None)],
+        };
+        assert_eq!(key(&check_conf, &status), None);
+        check_conf.handshake_policy = config::HandshakePolicy::Any;
+        assert_eq!(key(&check_conf, &status).as_deref(), Some("a"));
+    }
+}
+